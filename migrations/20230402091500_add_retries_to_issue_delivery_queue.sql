@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE issue_delivery_queue
+    ADD COLUMN n_retries INT NOT NULL DEFAULT 0,
+    ADD COLUMN execute_after timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN status TEXT NOT NULL DEFAULT 'pending',
+    ADD COLUMN last_error TEXT NULL;
@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE issue_deliveries(
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
+
+INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, status, error, updated_at)
+SELECT
+    newsletter_issue_id,
+    subscriber_email,
+    CASE WHEN status = 'dead_letter' THEN 'failed' ELSE 'queued' END,
+    last_error,
+    now()
+FROM issue_delivery_queue;
@@ -0,0 +1,21 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues
+    ALTER COLUMN published_at DROP NOT NULL,
+    ADD COLUMN created_at timestamptz NULL,
+    ADD COLUMN updated_at timestamptz NULL;
+
+UPDATE newsletter_issues SET created_at = published_at, updated_at = published_at;
+
+ALTER TABLE newsletter_issues
+    ALTER COLUMN created_at SET NOT NULL,
+    ALTER COLUMN updated_at SET NOT NULL;
+
+UPDATE newsletter_issues
+SET status = 'sent'
+WHERE status = 'sending'
+    AND NOT EXISTS (
+        SELECT 1
+        FROM issue_delivery_queue
+        WHERE issue_delivery_queue.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+            AND issue_delivery_queue.status = 'pending'
+    );
@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE subscriptions
+    ADD COLUMN topics TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN digest_frequency TEXT NOT NULL DEFAULT 'immediate';
+-- Tokens sent to confirm a change of address carry the address they confirm.
+ALTER TABLE subscription_tokens ADD COLUMN new_email TEXT NULL;
@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE subscription_tokens
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at timestamptz NULL,
+    ADD COLUMN used_at timestamptz NULL;
+-- Tokens issued so far get a full validity period from now on.
+UPDATE subscription_tokens SET expires_at = created_at + interval '1 day';
+ALTER TABLE subscription_tokens ALTER COLUMN expires_at SET NOT NULL;
+ALTER TABLE subscription_tokens ALTER COLUMN created_at DROP DEFAULT;
+-- Confirmed subscribers already used their confirmation token.
+UPDATE subscription_tokens SET used_at = now()
+WHERE new_email IS NULL
+    AND subscriber_id IN (SELECT id FROM subscriptions WHERE status = 'confirmed');
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Only the SHA-256 of a subscription token is stored from now on: existing tokens are hashed
+-- in place, so that the links already sent keep working.
+UPDATE subscription_tokens
+SET subscription_token = encode(sha256(convert_to(subscription_token, 'UTF8')), 'hex');
+ALTER TABLE subscription_tokens RENAME COLUMN subscription_token TO subscription_token_hash;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Left empty for the subscribers confirmed so far: when they confirmed was not recorded.
+ALTER TABLE subscriptions ADD COLUMN confirmed_at timestamptz NULL;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Issues without a topic go to every subscriber.
+ALTER TABLE newsletter_issues ADD COLUMN topic TEXT NULL;
@@ -0,0 +1,193 @@
+use crate::TypedSession;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::header::LOCATION,
+    middleware::Next,
+    rt::task::spawn_blocking,
+    HttpMessage, HttpResponse,
+};
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"[REDACTED]")
+            .finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] Box<dyn std::error::Error>),
+    #[error(transparent)]
+    UnexpectedError(#[from] Box<dyn std::error::Error>),
+}
+
+#[tracing::instrument(skip(credentials, pool), fields(username = %credentials.username))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    // Fall back to a dummy hash when the user does not exist, so that the response time
+    // does not reveal which usernames are registered.
+    let mut user_id = None;
+    let mut expected_password_hash = "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+        .to_string();
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool)
+            .await
+            .map_err(|e| AuthError::UnexpectedError(e.into()))?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    let current_span = tracing::Span::current();
+    spawn_blocking(move || {
+        current_span
+            .in_scope(|| verify_password_hash(&expected_password_hash, &credentials.password))
+    })
+    .await
+    .map_err(|e| AuthError::UnexpectedError(e.into()))?
+    .map_err(|e| match e {
+        argon2::password_hash::Error::Password => AuthError::InvalidCredentials(e.into()),
+        _ => AuthError::UnexpectedError(e.into()),
+    })?;
+
+    user_id.ok_or_else(|| AuthError::InvalidCredentials("Unknown username.".into()))
+}
+
+#[tracing::instrument(skip(expected_password_hash, password_candidate))]
+fn verify_password_hash(
+    expected_password_hash: &str,
+    password_candidate: &str,
+) -> Result<(), argon2::password_hash::Error> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash)?;
+    Argon2::default().verify_password(password_candidate.as_bytes(), &expected_password_hash)
+}
+
+#[tracing::instrument(skip(password, pool))]
+pub async fn change_password(
+    user_id: Uuid,
+    password: String,
+    pool: &PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current_span = tracing::Span::current();
+    let password_hash =
+        spawn_blocking(move || current_span.in_scope(|| compute_password_hash(&password)))
+            .await??;
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Creates the `admin` user with `password_hash` unless it exists already, so that no usable
+/// credential has to be committed to the repository.
+#[tracing::instrument(skip(password_hash, pool))]
+pub async fn seed_admin_user(
+    password_hash: &str,
+    pool: &PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    PasswordHash::new(password_hash)?;
+    sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash)
+        VALUES ($1, 'admin', $2)
+        ON CONFLICT (username) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        password_hash,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+fn compute_password_hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None)?,
+    )
+    .hash_password(password.as_bytes(), &salt)?
+    .to_string();
+    Ok(password_hash)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT username FROM users WHERE user_id = $1"#, user_id,)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.username)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| (row.user_id, row.password_hash));
+    Ok(row)
+}
+
+/// The id of the logged-in user, made available to handlers behind [`reject_anonymous_users`].
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(pub Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Middleware that redirects requests without a logged-in session to `/login`.
+pub async fn reject_anonymous_users(
+    session: TypedSession,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    match session.get_user_id().map_err(ErrorInternalServerError)? {
+        Some(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        None => {
+            let response = HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/login"))
+                .finish();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
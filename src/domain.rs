@@ -1,3 +1,5 @@
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+use time_tz::{timezones, PrimitiveDateTimeExt};
 use unicode_segmentation::UnicodeSegmentation;
 use validator::validate_email;
 
@@ -32,7 +34,7 @@ impl AsRef<str> for SubscriberName {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SubscriberEmail(String);
 
 impl SubscriberEmail {
@@ -57,9 +59,58 @@ impl AsRef<str> for SubscriberEmail {
     }
 }
 
+#[derive(Debug)]
+pub struct NewPassword(String);
+
+impl NewPassword {
+    pub fn parse(s: String) -> Result<Self, String> {
+        let length = s.graphemes(true).count();
+        if s.trim().is_empty() {
+            Err("The new password must not be blank.".to_string())
+        } else if length < 12 {
+            Err("The new password must be at least 12 characters long.".to_string())
+        } else if length > 128 {
+            Err("The new password must be at most 128 characters long.".to_string())
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for NewPassword {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// A wall-clock time in a named (IANA) timezone, such as the value of a `datetime-local` input.
+#[derive(Debug)]
+pub struct LocalDateTime(OffsetDateTime);
+
+impl LocalDateTime {
+    pub fn parse(date_time: &str, timezone: &str) -> Result<Self, String> {
+        let format =
+            format_description!("[year]-[month]-[day]T[hour]:[minute][optional [:[second]]]");
+        let date_time = PrimitiveDateTime::parse(date_time, &format)
+            .map_err(|_| format!("{date_time} is not a valid date and time."))?;
+        let tz = timezones::get_by_name(timezone)
+            .ok_or_else(|| format!("{timezone} is not a known timezone."))?;
+        // When clocks go back the wall-clock time happens twice: pick the first one.
+        let date_time = date_time
+            .assume_timezone(tz)
+            .take_first()
+            .ok_or_else(|| format!("{date_time} does not exist in {timezone}."))?;
+        Ok(Self(date_time))
+    }
+
+    pub fn into_inner(self) -> OffsetDateTime {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{SubscriberEmail, SubscriberName};
+    use super::{LocalDateTime, NewPassword, SubscriberEmail, SubscriberName};
     use fake::{faker::internet::en::SafeEmail, Fake};
     use quickcheck::Gen;
     use rand::{rngs::StdRng, SeedableRng};
@@ -135,4 +186,61 @@ mod tests {
         let email = "@domain.com".to_string();
         assert!(SubscriberEmail::parse(email).is_err());
     }
+
+    #[test]
+    fn a_12_grapheme_long_password_is_valid() {
+        let password = "a".repeat(12);
+        assert!(NewPassword::parse(password).is_ok());
+    }
+
+    #[test]
+    fn a_password_shorter_than_12_graphemes_is_rejected() {
+        let password = "a".repeat(11);
+        assert!(NewPassword::parse(password).is_err());
+    }
+
+    #[test]
+    fn a_password_longer_than_128_graphemes_is_rejected() {
+        let password = "a".repeat(129);
+        assert!(NewPassword::parse(password).is_err());
+    }
+
+    #[test]
+    fn whitespace_only_passwords_are_rejected() {
+        let password = " ".repeat(16);
+        assert!(NewPassword::parse(password).is_err());
+    }
+
+    #[test]
+    fn local_date_times_are_resolved_in_their_timezone() {
+        let date_time = LocalDateTime::parse("2023-04-11T09:00", "Europe/Paris").unwrap();
+        assert_eq!(
+            date_time.into_inner(),
+            time::macros::datetime!(2023-04-11 07:00 UTC)
+        );
+    }
+
+    #[test]
+    fn local_date_times_accept_seconds() {
+        let date_time = LocalDateTime::parse("2023-01-10T09:00:30", "America/New_York").unwrap();
+        assert_eq!(
+            date_time.into_inner(),
+            time::macros::datetime!(2023-01-10 14:00:30 UTC)
+        );
+    }
+
+    #[test]
+    fn unknown_timezones_are_rejected() {
+        assert!(LocalDateTime::parse("2023-04-11T09:00", "Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn malformed_local_date_times_are_rejected() {
+        assert!(LocalDateTime::parse("11/04/2023 09:00", "UTC").is_err());
+    }
+
+    #[test]
+    fn local_date_times_skipped_by_daylight_saving_are_rejected() {
+        assert!(LocalDateTime::parse("2023-03-26T02:30", "Europe/Paris").is_err());
+    }
 }
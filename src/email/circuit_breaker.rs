@@ -0,0 +1,275 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When a [`CircuitBreaker`] opens and closes again.
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    /// Consecutive transient failures after which the breaker opens.
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before letting a trial request through.
+    pub open_duration: Duration,
+    /// Consecutive successful trial requests after which the breaker closes again.
+    pub success_threshold: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        successes: u32,
+        trial_in_flight: bool,
+    },
+}
+
+/// Stops sending requests to a failing email provider for a while, so that callers fail fast
+/// instead of waiting out the timeout of every request.
+///
+/// The breaker starts closed. It opens after `failure_threshold` consecutive transient failures
+/// and rejects every request for `open_duration`. It then turns half-open and lets one trial
+/// request through at a time: `success_threshold` consecutive successes close it again, while a
+/// single failure opens it for another `open_duration`. A trial whose outcome is never reported,
+/// because the request was cancelled, lets the next trial through.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    provider: &'static str,
+    policy: BreakerPolicy,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(provider: &'static str, policy: BreakerPolicy) -> Self {
+        Self {
+            provider,
+            policy,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// A permit to send a request to the provider now, if it may be sent. Its outcome is
+    /// reported through the permit; dropping the permit without one reports nothing.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let trial = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } if Instant::now() >= until => {
+                tracing::info!(
+                    email_provider = self.provider,
+                    "Email circuit breaker half-open, sending a trial request"
+                );
+                *state = State::HalfOpen {
+                    successes: 0,
+                    trial_in_flight: true,
+                };
+                true
+            }
+            State::Open { .. } => return None,
+            State::HalfOpen {
+                successes,
+                trial_in_flight: false,
+            } => {
+                *state = State::HalfOpen {
+                    successes,
+                    trial_in_flight: true,
+                };
+                true
+            }
+            State::HalfOpen { .. } => return None,
+        };
+        Some(Permit {
+            breaker: self,
+            trial,
+            reported: false,
+        })
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => *state = State::Closed { failures: 0 },
+            State::HalfOpen { successes, .. } => {
+                let successes = successes + 1;
+                if successes >= self.policy.success_threshold {
+                    tracing::info!(
+                        email_provider = self.provider,
+                        "Email circuit breaker closed"
+                    );
+                    *state = State::Closed { failures: 0 };
+                } else {
+                    *state = State::HalfOpen {
+                        successes,
+                        trial_in_flight: false,
+                    };
+                }
+            }
+            State::Open { .. } => {}
+        }
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { failures } => {
+                let failures = failures + 1;
+                if failures >= self.policy.failure_threshold {
+                    tracing::warn!(
+                        email_provider = self.provider,
+                        failures,
+                        open_for = ?self.policy.open_duration,
+                        "Email circuit breaker opened"
+                    );
+                    *state = self.open();
+                } else {
+                    *state = State::Closed { failures };
+                }
+            }
+            State::HalfOpen { .. } => {
+                tracing::warn!(
+                    email_provider = self.provider,
+                    open_for = ?self.policy.open_duration,
+                    "Email circuit breaker trial request failed, reopening"
+                );
+                *state = self.open();
+            }
+            State::Open { .. } => {}
+        }
+    }
+
+    /// Lets the next trial through after one ended without an outcome.
+    fn abandon_trial(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::HalfOpen { successes, .. } = *state {
+            *state = State::HalfOpen {
+                successes,
+                trial_in_flight: false,
+            };
+        }
+    }
+
+    fn open(&self) -> State {
+        State::Open {
+            until: Instant::now() + self.policy.open_duration,
+        }
+    }
+}
+
+/// Permission to send one request through a [`CircuitBreaker`], returned by
+/// [`CircuitBreaker::try_acquire`].
+#[must_use]
+#[derive(Debug)]
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    /// Whether this is the trial request of a half-open breaker.
+    trial: bool,
+    reported: bool,
+}
+
+impl Permit<'_> {
+    pub fn record_success(mut self) {
+        self.reported = true;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.reported = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.reported {
+            tracing::info!(
+                email_provider = self.breaker.provider,
+                "Email circuit breaker trial request was cancelled"
+            );
+            self.breaker.abandon_trial();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerPolicy, CircuitBreaker};
+    use std::time::Duration;
+
+    fn breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerPolicy {
+                failure_threshold: 3,
+                open_duration,
+                success_threshold: 2,
+            },
+        )
+    }
+
+    #[test]
+    fn the_breaker_opens_after_consecutive_failures_only() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_some());
+
+        breaker.record_failure();
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn a_half_open_breaker_lets_one_trial_through_at_a_time() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+
+        let trial = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+        trial.record_success();
+        breaker.try_acquire().unwrap().record_success();
+
+        // Closed again: requests are no longer limited to one at a time.
+        let _first = breaker.try_acquire().unwrap();
+        let _second = breaker.try_acquire().unwrap();
+    }
+
+    #[test]
+    fn a_failed_trial_reopens_the_breaker() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+
+        breaker.try_acquire().unwrap().record_failure();
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            super::State::Open { .. }
+        ));
+    }
+
+    #[test]
+    fn a_cancelled_trial_lets_the_next_one_through() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+
+        let trial = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+        drop(trial);
+
+        breaker.try_acquire().unwrap().record_success();
+        breaker.try_acquire().unwrap().record_success();
+        assert_eq!(
+            *breaker.state.lock().unwrap(),
+            super::State::Closed { failures: 0 }
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::circuit_breaker::{BreakerPolicy, CircuitBreaker};
+use super::{BatchRecipient, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+
+/// Sends emails through the first available provider, in order of preference.
+///
+/// Each provider sits behind its own [`CircuitBreaker`]. An email goes to the next provider when
+/// the breaker of the previous one is open or when it fails transiently; permanent failures, such
+/// as a rejected address, are returned right away since another provider would reject the email
+/// too. Rate limiting sends the email to the next provider as well, but leaves the breaker alone:
+/// a provider asking to slow down is not failing.
+#[derive(Debug)]
+pub struct FailoverClient {
+    providers: Vec<(Arc<dyn EmailSender>, CircuitBreaker)>,
+}
+
+impl FailoverClient {
+    /// Takes the providers with their names, in order of preference.
+    pub fn new(
+        providers: Vec<(&'static str, Arc<dyn EmailSender>)>,
+        policy: BreakerPolicy,
+    ) -> Self {
+        Self {
+            providers: providers
+                .into_iter()
+                .map(|(name, provider)| (provider, CircuitBreaker::new(name, policy.clone())))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailSender for FailoverClient {
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError> {
+        let mut last_error = None;
+        for (provider, breaker) in &self.providers {
+            let Some(permit) = breaker.try_acquire() else {
+                continue;
+            };
+            let outcome = provider
+                .send_email_with_headers(to, subject, html_content, text_content, headers)
+                .await;
+            match outcome {
+                Err(error) if error.is_throttle() => {
+                    drop(permit);
+                    tracing::warn!(
+                        error.cause_chain = ?error,
+                        "The email provider is rate limiting requests, trying the next provider"
+                    );
+                    last_error = Some(error);
+                }
+                Err(error) if error.is_transient() => {
+                    permit.record_failure();
+                    tracing::warn!(
+                        error.cause_chain = ?error,
+                        "Failed to send an email, trying the next provider"
+                    );
+                    last_error = Some(error);
+                }
+                outcome => {
+                    permit.record_success();
+                    return outcome;
+                }
+            }
+        }
+        Err(last_error.unwrap_or(EmailError::Unavailable))
+    }
+
+    /// Sends the batch to the first available provider, then the recipients that failed
+    /// transiently to the next one. Only a batch that failed for all of its recipients, for
+    /// another reason than rate limiting, counts as a failure of the provider.
+    async fn send_batch(
+        &self,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        recipients: &[BatchRecipient],
+    ) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = recipients
+            .iter()
+            .map(|_| Err(EmailError::Unavailable))
+            .collect::<Vec<_>>();
+        // Positions of the recipients still to send to.
+        let mut pending = (0..recipients.len()).collect::<Vec<_>>();
+        for (provider, breaker) in &self.providers {
+            if pending.is_empty() {
+                break;
+            }
+            let Some(permit) = breaker.try_acquire() else {
+                continue;
+            };
+            let batch = pending
+                .iter()
+                .map(|&i| recipients[i].clone())
+                .collect::<Vec<_>>();
+            let batch_outcomes = provider
+                .send_batch(subject, html_content, text_content, &batch)
+                .await;
+            let mut failed = Vec::new();
+            for (i, outcome) in pending.iter().copied().zip(batch_outcomes) {
+                if matches!(&outcome, Err(error) if error.is_transient()) {
+                    failed.push(i);
+                }
+                outcomes[i] = outcome;
+            }
+            let throttled = failed
+                .iter()
+                .all(|&i| matches!(&outcomes[i], Err(error) if error.is_throttle()));
+            if failed.len() == pending.len() && throttled {
+                drop(permit);
+                tracing::warn!(
+                    n_recipients = failed.len(),
+                    "The email provider is rate limiting requests, trying the next provider"
+                );
+            } else if failed.len() == pending.len() {
+                permit.record_failure();
+                tracing::warn!(
+                    n_recipients = failed.len(),
+                    "Failed to send a batch of emails, trying the next provider"
+                );
+            } else {
+                permit.record_success();
+            }
+            pending = failed;
+        }
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BatchRecipient, BreakerPolicy, EmailError, EmailSender, FailoverClient, SubscriberEmail,
+    };
+    use crate::email::SendGridClient;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+    /// A provider answering every request with `status`.
+    async fn provider(status: u16) -> (Arc<dyn EmailSender>, MockServer) {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&mock_server)
+            .await;
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let client = SendGridClient::new(mock_server.uri(), "token".into(), from);
+        (Arc::new(client), mock_server)
+    }
+
+    fn failover(primary: Arc<dyn EmailSender>, secondary: Arc<dyn EmailSender>) -> FailoverClient {
+        FailoverClient::new(
+            vec![("primary", primary), ("secondary", secondary)],
+            BreakerPolicy {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(60),
+                success_threshold: 1,
+            },
+        )
+    }
+
+    async fn send(client: &FailoverClient) -> Result<(), EmailError> {
+        let to = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        client
+            .send_email(&to, "Subject", "<p>Body</p>", "Body")
+            .await
+    }
+
+    async fn n_requests(mock_server: &MockServer) -> usize {
+        mock_server.received_requests().await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn emails_go_to_the_primary_provider_while_it_is_up() {
+        let (primary, primary_server) = provider(200).await;
+        let (secondary, secondary_server) = provider(200).await;
+        let client = failover(primary, secondary);
+
+        send(&client).await.unwrap();
+
+        assert_eq!(n_requests(&primary_server).await, 1);
+        assert_eq!(n_requests(&secondary_server).await, 0);
+    }
+
+    #[tokio::test]
+    async fn emails_fail_over_and_skip_a_provider_with_an_open_breaker() {
+        let (primary, primary_server) = provider(503).await;
+        let (secondary, secondary_server) = provider(200).await;
+        let client = failover(primary, secondary);
+
+        for _ in 0..3 {
+            send(&client).await.unwrap();
+        }
+
+        // The primary breaker opened after two failures, so the third email skipped it.
+        assert_eq!(n_requests(&primary_server).await, 2);
+        assert_eq!(n_requests(&secondary_server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_emails_fail_over_without_opening_the_breaker() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(3)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let primary = Arc::new(SendGridClient::new(mock_server.uri(), "token".into(), from));
+        let (secondary, secondary_server) = provider(200).await;
+        let client = failover(primary, secondary);
+
+        for _ in 0..3 {
+            send(&client).await.unwrap();
+        }
+        // Three 429s in a row, more than the failure threshold, left the primary breaker closed.
+        send(&client).await.unwrap();
+
+        assert_eq!(n_requests(&mock_server).await, 4);
+        assert_eq!(n_requests(&secondary_server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_batches_fail_over_without_opening_the_breaker() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(3)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .mount(&mock_server)
+            .await;
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let primary = Arc::new(SendGridClient::new(mock_server.uri(), "token".into(), from));
+        let (secondary, secondary_server) = provider(202).await;
+        let client = failover(primary, secondary);
+        let recipients = [BatchRecipient {
+            email: SubscriberEmail::parse("ursula@example.com".into()).unwrap(),
+            substitutions: vec![],
+            headers: vec![],
+        }];
+
+        for _ in 0..4 {
+            let outcomes = client
+                .send_batch("Subject", "<p>Body</p>", "Body", &recipients)
+                .await;
+            assert!(outcomes.iter().all(Result::is_ok));
+        }
+
+        assert_eq!(n_requests(&mock_server).await, 4);
+        assert_eq!(n_requests(&secondary_server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_do_not_fail_over() {
+        let (primary, _primary_server) = provider(400).await;
+        let (secondary, secondary_server) = provider(200).await;
+        let client = failover(primary, secondary);
+
+        assert!(send(&client).await.is_err());
+
+        assert_eq!(n_requests(&secondary_server).await, 0);
+    }
+
+    #[tokio::test]
+    async fn sending_fails_fast_once_every_breaker_is_open() {
+        let (primary, primary_server) = provider(503).await;
+        let (secondary, secondary_server) = provider(503).await;
+        let client = failover(primary, secondary);
+
+        for _ in 0..2 {
+            assert!(send(&client).await.is_err());
+        }
+        let error = send(&client).await.unwrap_err();
+
+        assert!(matches!(error, EmailError::Unavailable));
+        assert!(error.is_transient());
+        assert_eq!(n_requests(&primary_server).await, 2);
+        assert_eq!(n_requests(&secondary_server).await, 2);
+    }
+
+    #[tokio::test]
+    async fn a_trial_email_dropped_mid_send_does_not_block_the_provider() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let client = FailoverClient::new(
+            vec![(
+                "primary",
+                Arc::new(SendGridClient::new(mock_server.uri(), "token".into(), from)),
+            )],
+            BreakerPolicy {
+                failure_threshold: 1,
+                open_duration: Duration::ZERO,
+                success_threshold: 1,
+            },
+        );
+
+        assert!(send(&client).await.is_err());
+        // The trial email is dropped while waiting for the provider, as when a client disconnects.
+        let trial = actix_web::rt::time::timeout(Duration::from_millis(100), send(&client)).await;
+        assert!(trial.is_err());
+
+        send(&client).await.unwrap();
+        assert_eq!(n_requests(&mock_server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn batches_fail_over_to_the_next_provider() {
+        let (primary, _primary_server) = provider(503).await;
+        let (secondary, secondary_server) = provider(202).await;
+        let client = failover(primary, secondary);
+        let recipients =
+            ["ursula@example.com", "octavia@example.com"].map(|address| BatchRecipient {
+                email: SubscriberEmail::parse(address.into()).unwrap(),
+                substitutions: vec![],
+                headers: vec![],
+            });
+
+        let outcomes = client
+            .send_batch("Subject", "<p>Body</p>", "Body", &recipients)
+            .await;
+
+        assert!(outcomes.iter().all(Result::is_ok));
+        assert_eq!(n_requests(&secondary_server).await, 1);
+    }
+}
@@ -0,0 +1,310 @@
+mod circuit_breaker;
+mod failover;
+mod outbox;
+mod postmark;
+mod rate_limit;
+mod sendgrid;
+mod smtp;
+
+pub use circuit_breaker::{BreakerPolicy, CircuitBreaker};
+pub use failover::FailoverClient;
+pub use outbox::{OutboxClient, OutboxEmail};
+pub use postmark::PostmarkClient;
+pub use rate_limit::RateLimiter;
+pub use sendgrid::SendGridClient;
+pub use smtp::SmtpClient;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+
+use crate::domain::SubscriberEmail;
+use crate::settings::{EmailBackend, Settings};
+
+/// Sends emails through a provider. Routes and workers only depend on this trait, so the
+/// provider can be swapped through [`Settings::email_backend`].
+#[async_trait]
+pub trait EmailSender: std::fmt::Debug + Send + Sync {
+    /// Sends an email with extra headers, e.g. `List-Unsubscribe`, added to the email itself.
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError>;
+
+    async fn send_email(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), EmailError> {
+        self.send_email_with_headers(to, subject, html_content, text_content, &[])
+            .await
+    }
+
+    /// Sends the same email to many recipients, with the substitutions of each recipient
+    /// applied to the subject and content. Returns the outcome for each recipient, in order.
+    ///
+    /// Sends one email at a time unless the provider supports batches.
+    async fn send_batch(
+        &self,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        recipients: &[BatchRecipient],
+    ) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            let headers = recipient
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+            let outcome = self
+                .send_email_with_headers(
+                    &recipient.email,
+                    &recipient.substitute(subject),
+                    &recipient.substitute(html_content),
+                    &recipient.substitute(text_content),
+                    &headers,
+                )
+                .await;
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+}
+
+/// A recipient of [`EmailSender::send_batch`].
+#[derive(Clone, Debug)]
+pub struct BatchRecipient {
+    pub email: SubscriberEmail,
+    /// Placeholders of the subject and content, with the values that replace them for this
+    /// recipient.
+    pub substitutions: Vec<(String, String)>,
+    /// Extra headers added to the email sent to this recipient.
+    pub headers: Vec<(String, String)>,
+}
+
+impl BatchRecipient {
+    /// Replaces the placeholders in `content` with the values for this recipient.
+    pub fn substitute(&self, content: &str) -> String {
+        self.substitutions
+            .iter()
+            .fold(content.to_string(), |content, (placeholder, value)| {
+                content.replace(placeholder, value)
+            })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EmailError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Failed to build the email")]
+    InvalidMessage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Failed to write the email to the outbox directory")]
+    Io(#[from] std::io::Error),
+    #[error("No email provider is available, their circuit breakers are open")]
+    Unavailable,
+    #[error("The email provider is rate limiting requests")]
+    RateLimited { retry_after: Option<Duration> },
+    /// A batch request failed as a whole, for each of its recipients.
+    #[error("Failed to send a batch of emails: {message}")]
+    BatchFailed {
+        message: String,
+        transient: bool,
+        throttled: bool,
+        retry_after: Option<Duration>,
+    },
+    /// The provider rejected one of the recipients of a batch.
+    #[error("The email provider rejected the recipient: {0}")]
+    RecipientRejected(String),
+}
+
+impl EmailError {
+    /// Whether sending again later may succeed: timeouts, connection failures, rate limiting
+    /// and server errors are transient; other rejections mean the email will never go through.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EmailError::Http(error) => match error.status() {
+                Some(status) => {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                None => error.is_timeout() || error.is_connect() || error.is_request(),
+            },
+            EmailError::Smtp(error) => {
+                error.is_transient()
+                    || error.is_timeout()
+                    || !(error.is_permanent()
+                        || error.is_client()
+                        || error.is_response()
+                        || error.is_tls())
+            }
+            EmailError::Unavailable | EmailError::RateLimited { .. } => true,
+            EmailError::BatchFailed { transient, .. } => *transient,
+            EmailError::InvalidMessage(_)
+            | EmailError::Io(_)
+            | EmailError::RecipientRejected(_) => false,
+        }
+    }
+
+    /// Whether the email was held back by rate limiting, by the provider or by our own limiter
+    /// while it is paused. The provider is up: sending again after `retry_after` should work, so
+    /// this is no sign of an outage.
+    pub fn is_throttle(&self) -> bool {
+        match self {
+            EmailError::RateLimited { .. } => true,
+            EmailError::BatchFailed { throttled, .. } => *throttled,
+            _ => false,
+        }
+    }
+
+    /// How long the provider asked to wait before sending again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            EmailError::RateLimited { retry_after }
+            | EmailError::BatchFailed { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Fails each recipient of a batch with this error.
+    fn for_batch(&self) -> EmailError {
+        EmailError::BatchFailed {
+            message: self.to_string(),
+            transient: self.is_transient(),
+            throttled: self.is_throttle(),
+            retry_after: self.retry_after(),
+        }
+    }
+}
+
+/// Builds the sender for the providers selected in the settings: the primary one and, when
+/// configured, a fallback, each behind a circuit breaker.
+pub fn build_email_sender(
+    settings: &Settings,
+) -> Result<Arc<dyn EmailSender>, Box<dyn std::error::Error>> {
+    let from = SubscriberEmail::parse(settings.email_sender.clone())?;
+    if !(settings.email_requests_per_sec.is_finite() && settings.email_requests_per_sec > 0.0) {
+        return Err("`email_requests_per_sec` must be a positive number".into());
+    }
+    let mut providers = vec![(
+        settings.email_backend.as_str(),
+        build_provider(
+            settings.email_backend,
+            &settings.email_base_url,
+            &settings.email_auth_token,
+            settings.email_smtp_username.as_deref(),
+            from.clone(),
+            settings,
+        )?,
+    )];
+    if let Some(backend) = settings.email_fallback_backend {
+        let base_url = settings
+            .email_fallback_base_url
+            .as_deref()
+            .ok_or("A fallback email provider requires `email_fallback_base_url`")?;
+        providers.push((
+            backend.as_str(),
+            build_provider(
+                backend,
+                base_url,
+                settings
+                    .email_fallback_auth_token
+                    .as_deref()
+                    .unwrap_or_default(),
+                settings.email_fallback_smtp_username.as_deref(),
+                from,
+                settings,
+            )?,
+        ));
+    }
+    let policy = BreakerPolicy {
+        failure_threshold: settings.email_breaker_failure_threshold,
+        open_duration: Duration::from_secs(settings.email_breaker_open_secs),
+        success_threshold: settings.email_breaker_success_threshold,
+    };
+    Ok(Arc::new(FailoverClient::new(providers, policy)))
+}
+
+/// Builds a single provider. Those behind an HTTP API each get their own rate limit.
+fn build_provider(
+    backend: EmailBackend,
+    base_url: &str,
+    auth_token: &str,
+    smtp_username: Option<&str>,
+    from: SubscriberEmail,
+    settings: &Settings,
+) -> Result<Arc<dyn EmailSender>, EmailError> {
+    let (base_url, auth_token) = (base_url.to_string(), auth_token.to_string());
+    let rate_limiter = || {
+        RateLimiter::new(
+            settings.email_requests_per_sec,
+            settings.email_request_burst,
+        )
+    };
+    Ok(match backend {
+        EmailBackend::SendGrid => Arc::new(
+            SendGridClient::new(base_url, auth_token, from).with_rate_limiter(rate_limiter()),
+        ),
+        EmailBackend::Postmark => Arc::new(
+            PostmarkClient::new(base_url, auth_token, from).with_rate_limiter(rate_limiter()),
+        ),
+        EmailBackend::Smtp => Arc::new(SmtpClient::new(
+            &base_url,
+            smtp_username.map(Into::into),
+            auth_token,
+            from,
+        )?),
+        EmailBackend::Outbox => {
+            let outbox_directory = settings.email_outbox_directory.as_ref();
+            Arc::new(OutboxClient::new(from, outbox_directory.map(Into::into)))
+        }
+    })
+}
+
+/// Builds a MIME email with both a plain text and an HTML part.
+fn build_message(
+    from: &SubscriberEmail,
+    to: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+    headers: &[(&str, &str)],
+) -> Result<Message, EmailError> {
+    let mut message = Message::builder()
+        .from(mailbox(from)?)
+        .to(mailbox(to)?)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(
+            text_content.to_string(),
+            html_content.to_string(),
+        ))
+        .map_err(|e| EmailError::InvalidMessage(e.into()))?;
+    for (name, value) in headers {
+        let name = HeaderName::new_from_ascii(name.to_string())
+            .map_err(|e| EmailError::InvalidMessage(e.into()))?;
+        message
+            .headers_mut()
+            .insert_raw(HeaderValue::new(name, value.to_string()));
+    }
+    Ok(message)
+}
+
+fn mailbox(email: &SubscriberEmail) -> Result<Mailbox, EmailError> {
+    email
+        .as_ref()
+        .parse()
+        .map_err(|e: lettre::address::AddressError| EmailError::InvalidMessage(e.into()))
+}
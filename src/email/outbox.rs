@@ -0,0 +1,182 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use actix_web::rt::task::spawn_blocking;
+use async_trait::async_trait;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::{build_message, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+
+/// An email kept by the [`OutboxClient`] instead of being sent.
+#[derive(Clone, Debug)]
+pub struct OutboxEmail {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Keeps emails instead of sending them, for local development and tests.
+///
+/// Every email is appended to an in-memory outbox and, when a directory is configured, also
+/// written there as an `.eml` file that any mail client can open.
+#[derive(Debug)]
+pub struct OutboxClient {
+    from: SubscriberEmail,
+    directory: Option<PathBuf>,
+    outbox: Mutex<Vec<OutboxEmail>>,
+}
+
+impl OutboxClient {
+    pub fn new(from: SubscriberEmail, directory: Option<PathBuf>) -> Self {
+        Self {
+            from,
+            directory,
+            outbox: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The emails kept so far, oldest first.
+    pub fn emails(&self) -> Vec<OutboxEmail> {
+        self.outbox.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EmailSender for OutboxClient {
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError> {
+        let message = build_message(&self.from, to, subject, html_content, text_content, headers)?;
+        if let Some(directory) = &self.directory {
+            // Prefixed with the time, so that listing the directory shows the emails in order.
+            let path = directory.join(format!(
+                "{}-{}.eml",
+                OffsetDateTime::now_utc().unix_timestamp_nanos(),
+                Uuid::new_v4()
+            ));
+            let directory = directory.clone();
+            let contents = message.formatted();
+            spawn_blocking(move || {
+                std::fs::create_dir_all(directory)?;
+                std::fs::write(path, contents)
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+        }
+        self.outbox.lock().unwrap().push(OutboxEmail {
+            to: to.as_ref().to_string(),
+            subject: subject.to_string(),
+            html: html_content.to_string(),
+            text: text_content.to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailSender, OutboxClient, SubscriberEmail};
+    use crate::email::BatchRecipient;
+    use uuid::Uuid;
+
+    fn email(address: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(address.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn emails_are_kept_in_the_outbox_in_order() {
+        let client = OutboxClient::new(email("newsletter@example.com"), None);
+
+        client
+            .send_email(&email("ursula@example.com"), "First", "<p>1</p>", "1")
+            .await
+            .unwrap();
+        client
+            .send_email_with_headers(
+                &email("ursula@example.com"),
+                "Second",
+                "<p>2</p>",
+                "2",
+                &[("List-Unsubscribe", "<https://example.com/u>")],
+            )
+            .await
+            .unwrap();
+
+        let emails = client.emails();
+        assert_eq!(emails.len(), 2);
+        assert_eq!(emails[0].to, "ursula@example.com");
+        assert_eq!(emails[0].subject, "First");
+        assert_eq!(emails[1].text, "2");
+        assert_eq!(
+            emails[1].headers,
+            vec![(
+                "List-Unsubscribe".to_string(),
+                "<https://example.com/u>".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn emails_are_written_to_the_directory_and_kept_in_the_outbox() {
+        let directory = std::env::temp_dir().join(format!("outbox-{}", Uuid::new_v4()));
+        let client = OutboxClient::new(email("newsletter@example.com"), Some(directory.clone()));
+
+        client
+            .send_email(&email("ursula@example.com"), "Hello", "<p>Hi!</p>", "Hi!")
+            .await
+            .unwrap();
+
+        let files = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let eml = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(eml.contains("To: ursula@example.com"));
+        assert!(eml.contains("Subject: Hello"));
+        assert!(eml.contains("<p>Hi!</p>"));
+        let emails = client.emails();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, "ursula@example.com");
+        assert_eq!(emails[0].html, "<p>Hi!</p>");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn batches_are_sent_one_email_per_recipient_with_their_substitutions() {
+        let client = OutboxClient::new(email("newsletter@example.com"), None);
+        let recipients = ["ursula", "octavia"].map(|name| BatchRecipient {
+            email: email(&format!("{name}@example.com")),
+            substitutions: vec![("-name-".to_string(), name.to_string())],
+            headers: vec![("X-Reader".to_string(), name.to_string())],
+        });
+
+        let outcomes = client
+            .send_batch("Hi -name-", "<p>Hi -name-</p>", "Hi -name-", &recipients)
+            .await;
+
+        assert!(outcomes.iter().all(Result::is_ok));
+        let emails = client.emails();
+        assert_eq!(emails[0].subject, "Hi ursula");
+        assert_eq!(emails[1].to, "octavia@example.com");
+        assert_eq!(emails[1].html, "<p>Hi octavia</p>");
+        assert_eq!(
+            emails[1].headers,
+            vec![("X-Reader".to_string(), "octavia".to_string())]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use reqwest::Client;
+
+use super::rate_limit::{send_rate_limited, RateLimiter};
+use super::{EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+
+/// Sends emails through a Postmark-style JSON API.
+#[derive(Debug)]
+pub struct PostmarkClient {
+    http_client: Client,
+    base_url: String,
+    server_token: String,
+    from: SubscriberEmail,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl PostmarkClient {
+    pub fn new(base_url: String, server_token: String, from: SubscriberEmail) -> Self {
+        let http_client = Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        Self {
+            http_client,
+            base_url,
+            server_token,
+            from,
+            rate_limiter: None,
+        }
+    }
+
+    /// Keeps the requests to the provider within the limits of `rate_limiter`.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+}
+
+#[async_trait]
+impl EmailSender for PostmarkClient {
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError> {
+        // based on https://postmarkapp.com/developer/api/email-api#send-a-single-email
+        let body = EmailRequestBody {
+            from: self.from.as_ref(),
+            to: to.as_ref(),
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            headers: headers
+                .iter()
+                .map(|&(name, value)| Header { name, value })
+                .collect(),
+            message_stream: "outbound",
+        };
+        let request = self
+            .http_client
+            .post(&self.base_url)
+            .header("X-Postmark-Server-Token", &self.server_token)
+            .header("Accept", "application/json")
+            .json(&body);
+        send_rate_limited(self.rate_limiter.as_ref(), request)
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailRequestBody<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header<'a>>,
+    message_stream: &'a str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Header<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailSender, PostmarkClient, SubscriberEmail};
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{faker::internet::en::SafeEmail, Fake};
+    use wiremock::matchers::{any, body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn get_mock_client() -> (PostmarkClient, MockServer) {
+        let mock_server = MockServer::start().await;
+        let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = PostmarkClient::new(mock_server.uri(), "server-token".into(), from);
+        (email_client, mock_server)
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_postmark_request() -> Result<(), Box<dyn std::error::Error>> {
+        let (email_client, mock_server) = get_mock_client().await;
+        let to = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+
+        Mock::given(method("POST"))
+            .and(header("X-Postmark-Server-Token", "server-token"))
+            .and(header("Content-Type", "application/json"))
+            .and(body_partial_json(serde_json::json!({
+                "To": to.as_ref(),
+                "Subject": subject,
+                "HtmlBody": content,
+                "TextBody": content,
+                "Headers": [{ "Name": "List-Unsubscribe", "Value": "<https://example.com/u>" }],
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_email_with_headers(
+                &to,
+                &subject,
+                &content,
+                &content,
+                &[("List-Unsubscribe", "<https://example.com/u>")],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_rejected_email_is_not_transient() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let to = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let error = email_client
+            .send_email(&to, "Subject", "<p>Body</p>", "Body")
+            .await
+            .unwrap_err();
+        assert!(!error.is_transient());
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::sleep;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+use super::EmailError;
+
+/// Limits the rate of requests sent to an email provider with a token bucket.
+///
+/// The bucket holds up to `burst` tokens and is refilled with `requests_per_sec` tokens per
+/// second. Every request takes a token, waiting for one when the bucket is empty. When the
+/// provider answers with `429 Too Many Requests`, the bucket is paused until its `Retry-After`,
+/// and requests fail right away until then rather than waiting for what may be hours.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_sec: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Panics unless `requests_per_sec` is a positive number.
+    pub fn new(requests_per_sec: f64, burst: u32) -> Self {
+        assert!(
+            requests_per_sec.is_finite() && requests_per_sec > 0.0,
+            "The request rate must be a positive number, got {requests_per_sec}"
+        );
+        let burst = f64::from(burst.max(1));
+        Self {
+            requests_per_sec,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent, or fails with [`EmailError::RateLimited`] while the
+    /// provider asked to pause.
+    #[tracing::instrument(
+        name = "Waiting for the email rate limit",
+        skip(self),
+        fields(throttled_for = tracing::field::Empty, tokens_left = tracing::field::Empty)
+    )]
+    pub async fn acquire(&self) -> Result<(), EmailError> {
+        let mut throttled_for = Duration::ZERO;
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.requests_per_sec).min(self.burst);
+                bucket.refilled_at = now;
+                match bucket.paused_until {
+                    Some(paused_until) if paused_until > now => {
+                        return Err(EmailError::RateLimited {
+                            retry_after: Some(paused_until - now),
+                        });
+                    }
+                    _ if bucket.tokens >= 1.0 => {
+                        bucket.tokens -= 1.0;
+                        let span = tracing::Span::current();
+                        span.record("throttled_for", tracing::field::debug(throttled_for));
+                        span.record("tokens_left", bucket.tokens.floor() as u64);
+                        return Ok(());
+                    }
+                    _ => Duration::from_secs_f64((1.0 - bucket.tokens) / self.requests_per_sec),
+                }
+            };
+            throttled_for += wait;
+            sleep(wait).await;
+        }
+    }
+
+    /// Pauses every request for `delay`, unless they are already paused for longer.
+    pub fn pause_for(&self, delay: Duration) {
+        let paused_until = Instant::now() + delay;
+        let mut bucket = self.bucket.lock().unwrap();
+        if !matches!(bucket.paused_until, Some(until) if until >= paused_until) {
+            tracing::warn!(
+                paused_for = ?delay,
+                "The email provider is rate limiting requests, pausing them"
+            );
+            bucket.paused_until = Some(paused_until);
+        }
+    }
+}
+
+/// Sends a request to an HTTP provider, within the rate limit when there is one.
+///
+/// `429 Too Many Requests` responses become [`EmailError::RateLimited`] and pause the limiter
+/// for as long as the provider asked; requests made during the pause fail the same way.
+pub async fn send_rate_limited(
+    limiter: Option<&RateLimiter>,
+    request: RequestBuilder,
+) -> Result<Response, EmailError> {
+    if let Some(limiter) = limiter {
+        limiter.acquire().await?;
+    }
+    let response = request.send().await?;
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return Ok(response);
+    }
+    let retry_after = retry_after(&response);
+    if let (Some(limiter), Some(delay)) = (limiter, retry_after) {
+        limiter.pause_for(delay);
+    }
+    Err(EmailError::RateLimited { retry_after })
+}
+
+/// How long the provider asked to wait before the next request, from the `Retry-After` header:
+/// either a number of seconds or a date.
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    (date - OffsetDateTime::now_utc()).try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_after, EmailError, RateLimiter};
+    use std::time::{Duration, Instant};
+    use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn requests_beyond_the_burst_wait_for_the_bucket_to_refill() {
+        let limiter = RateLimiter::new(20.0, 2);
+        let start = Instant::now();
+
+        limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(25));
+
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(45));
+    }
+
+    #[tokio::test]
+    async fn paused_requests_fail_right_away_until_the_pause_ends() {
+        let limiter = RateLimiter::new(100.0, 10);
+        limiter.pause_for(Duration::from_millis(100));
+        limiter.pause_for(Duration::from_millis(10));
+        let start = Instant::now();
+
+        let error = limiter.acquire().await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(10));
+        let EmailError::RateLimited {
+            retry_after: Some(retry_after),
+        } = error
+        else {
+            panic!("Expected a rate limit error, got {error:?}");
+        };
+        assert!(
+            retry_after > Duration::from_millis(80) && retry_after <= Duration::from_millis(100)
+        );
+
+        actix_web::rt::time::sleep(retry_after).await;
+        limiter.acquire().await.unwrap();
+    }
+
+    async fn response_with_retry_after(value: &str) -> reqwest::Response {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", value))
+            .mount(&mock_server)
+            .await;
+        reqwest::get(mock_server.uri()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_read_as_seconds_or_as_a_date() {
+        let response = response_with_retry_after("120").await;
+        assert_eq!(retry_after(&response), Some(Duration::from_secs(120)));
+
+        let in_a_minute = time::OffsetDateTime::now_utc() + time::Duration::minutes(1);
+        // HTTP dates are in GMT, e.g. `Wed, 21 Oct 2015 07:28:00 GMT`.
+        let date = in_a_minute
+            .format(&time::format_description::well_known::Rfc2822)
+            .unwrap()
+            .replace("+0000", "GMT");
+        let response = response_with_retry_after(&date).await;
+        let delay = retry_after(&response).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+
+        let response = response_with_retry_after("soon").await;
+        assert_eq!(retry_after(&response), None);
+    }
+}
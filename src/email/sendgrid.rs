@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use reqwest::{Client, Response, StatusCode};
+
+use super::rate_limit::{send_rate_limited, RateLimiter};
+use super::{BatchRecipient, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+
+/// Sends emails through the SendGrid v3 mail send API.
+#[derive(Debug)]
+pub struct SendGridClient {
+    http_client: Client,
+    base_url: String,
+    auth_token: String,
+    from: SubscriberEmail,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl SendGridClient {
+    pub fn new(base_url: String, auth_token: String, from: SubscriberEmail) -> Self {
+        let http_client = Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+        Self {
+            http_client,
+            base_url,
+            auth_token,
+            from,
+            rate_limiter: None,
+        }
+    }
+
+    /// Keeps the requests to the provider within the limits of `rate_limiter`.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+}
+
+#[async_trait]
+impl EmailSender for SendGridClient {
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError> {
+        // based on https://docs.sendgrid.com/api-reference/mail-send/mail-send#body
+        let body = EmailRequestBody {
+            personalizations: vec![Personalization {
+                to: vec![EmailAddress { email: to.as_ref() }],
+                subject,
+                substitutions: HashMap::new(),
+                headers: HashMap::new(),
+            }],
+            from: EmailAddress {
+                email: self.from.as_ref(),
+            },
+            content: content(html_content, text_content),
+            headers: headers.iter().copied().collect(),
+        };
+        self.post(&body).await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Sends up to [`MAX_PERSONALIZATIONS`] recipients per request, each with their own
+    /// `personalizations` entry. SendGrid rejects a whole request when some of its recipients
+    /// are invalid: those are reported as rejected and the request is sent again without them.
+    async fn send_batch(
+        &self,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        recipients: &[BatchRecipient],
+    ) -> Vec<Result<(), EmailError>> {
+        let mut outcomes = Vec::with_capacity(recipients.len());
+        for chunk in recipients.chunks(MAX_PERSONALIZATIONS) {
+            let mut chunk_outcomes = chunk.iter().map(|_| Ok(())).collect::<Vec<_>>();
+            // Positions, within the chunk, of the recipients still to send to.
+            let mut pending = (0..chunk.len()).collect::<Vec<_>>();
+            while !pending.is_empty() {
+                let batch = pending.iter().map(|&i| &chunk[i]);
+                match self
+                    .send_chunk(subject, html_content, text_content, batch)
+                    .await
+                {
+                    Ok(()) => pending.clear(),
+                    Err(BatchError::Rejected(rejected)) => {
+                        for (position, message) in rejected.into_iter().rev() {
+                            let i = pending.remove(position);
+                            chunk_outcomes[i] = Err(EmailError::RecipientRejected(message));
+                        }
+                    }
+                    Err(BatchError::Failed(error)) => {
+                        for i in pending.drain(..) {
+                            chunk_outcomes[i] = Err(error.for_batch());
+                        }
+                    }
+                }
+            }
+            outcomes.extend(chunk_outcomes);
+        }
+        outcomes
+    }
+}
+
+/// The most recipients SendGrid accepts in a single request.
+pub const MAX_PERSONALIZATIONS: usize = 1000;
+
+enum BatchError {
+    /// Some recipients were rejected, by position in the request, so nothing was sent.
+    Rejected(BTreeMap<usize, String>),
+    Failed(EmailError),
+}
+
+impl From<EmailError> for BatchError {
+    fn from(e: EmailError) -> Self {
+        BatchError::Failed(e)
+    }
+}
+
+impl SendGridClient {
+    async fn send_chunk(
+        &self,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        recipients: impl Iterator<Item = &BatchRecipient>,
+    ) -> Result<(), BatchError> {
+        let body = EmailRequestBody {
+            personalizations: recipients
+                .map(|recipient| Personalization {
+                    to: vec![EmailAddress {
+                        email: recipient.email.as_ref(),
+                    }],
+                    subject,
+                    substitutions: pairs(&recipient.substitutions),
+                    headers: pairs(&recipient.headers),
+                })
+                .collect(),
+            from: EmailAddress {
+                email: self.from.as_ref(),
+            },
+            content: content(html_content, text_content),
+            headers: HashMap::new(),
+        };
+        let n_personalizations = body.personalizations.len();
+        let response = self.post(&body).await?;
+        let Err(error) = response.error_for_status_ref() else {
+            return Ok(());
+        };
+        if response.status() == StatusCode::BAD_REQUEST {
+            if let Ok(errors) = response.json::<ErrorResponseBody>().await {
+                let rejected = errors.rejected_personalizations(n_personalizations);
+                if !rejected.is_empty() {
+                    return Err(BatchError::Rejected(rejected));
+                }
+            }
+        }
+        Err(EmailError::from(error).into())
+    }
+
+    async fn post(&self, body: &EmailRequestBody<'_>) -> Result<Response, EmailError> {
+        let request = self
+            .http_client
+            .post(&self.base_url)
+            .header("Authorization", format!("Bearer {}", self.auth_token))
+            .json(body);
+        send_rate_limited(self.rate_limiter.as_ref(), request).await
+    }
+}
+
+fn content<'a>(html_content: &'a str, text_content: &'a str) -> Vec<Content<'a>> {
+    vec![
+        Content {
+            content_type: "text/plain",
+            value: text_content,
+        },
+        Content {
+            content_type: "text/html",
+            value: html_content,
+        },
+    ]
+}
+
+fn pairs(pairs: &[(String, String)]) -> HashMap<&str, &str> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct EmailRequestBody<'a> {
+    personalizations: Vec<Personalization<'a>>,
+    from: EmailAddress<'a>,
+    content: Vec<Content<'a>>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'a str, &'a str>,
+}
+
+#[derive(serde::Serialize)]
+struct Personalization<'a> {
+    to: Vec<EmailAddress<'a>>,
+    subject: &'a str,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    substitutions: HashMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'a str, &'a str>,
+}
+
+#[derive(serde::Serialize)]
+struct EmailAddress<'a> {
+    email: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct Content<'a> {
+    #[serde(rename = "type")]
+    content_type: &'a str,
+    value: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorResponseBody {
+    errors: Vec<ErrorDetail>,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorDetail {
+    message: String,
+    field: Option<String>,
+}
+
+impl ErrorResponseBody {
+    /// The errors about a given personalization, e.g. `personalizations.3.to.0.email`, by
+    /// position of the personalization.
+    fn rejected_personalizations(self, n_personalizations: usize) -> BTreeMap<usize, String> {
+        let mut rejected = BTreeMap::new();
+        for error in self.errors {
+            let position = error.field.as_deref().and_then(|field| {
+                let field = field.strip_prefix("personalizations")?;
+                let digits = field.trim_start_matches(['.', '[']);
+                let end = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                digits[..end].parse::<usize>().ok()
+            });
+            if let Some(position) = position.filter(|&position| position < n_personalizations) {
+                rejected.entry(position).or_insert(error.message);
+            }
+        }
+        rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BatchRecipient, EmailError, EmailSender, RateLimiter, SendGridClient, SubscriberEmail,
+    };
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{faker::internet::en::SafeEmail, Fake, Faker};
+    use std::time::{Duration, Instant};
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn get_mock_client() -> (SendGridClient, MockServer) {
+        let mock_server = MockServer::start().await;
+        let auth_token = Faker.fake();
+        let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = SendGridClient::new(mock_server.uri(), auth_token, from);
+        (email_client, mock_server)
+    }
+
+    async fn get_mock_req_data() -> (SubscriberEmail, String, String) {
+        let to = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+        (to, subject, content)
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_tobase_url() -> Result<(), Box<dyn std::error::Error>> {
+        let (email_client, mock_server) = get_mock_client().await;
+        let (to, subject, content) = get_mock_req_data().await;
+
+        Mock::given(any())
+            .and(header_exists("Authorization"))
+            .and(header("Content-Type", "application/json"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_email(&to, &subject, &content, &content)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (email_client, mock_server) = get_mock_client().await;
+        let (to, subject, content) = get_mock_req_data().await;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_email(&to, &subject, &content, &content)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let (email_client, mock_server) = get_mock_client().await;
+        let (to, subject, content) = get_mock_req_data().await;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let res = email_client
+            .send_email(&to, &subject, &content, &content)
+            .await;
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_with_headers_passes_them_along() -> Result<(), Box<dyn std::error::Error>> {
+        let (email_client, mock_server) = get_mock_client().await;
+        let (to, subject, content) = get_mock_req_data().await;
+
+        Mock::given(body_partial_json(serde_json::json!({
+            "headers": { "List-Unsubscribe": "<https://example.com/unsubscribe>" }
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        email_client
+            .send_email_with_headers(
+                &to,
+                &subject,
+                &content,
+                &content,
+                &[("List-Unsubscribe", "<https://example.com/unsubscribe>")],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    fn batch_recipient(n: usize) -> BatchRecipient {
+        BatchRecipient {
+            email: SubscriberEmail::parse(format!("reader{n}@example.com")).unwrap(),
+            substitutions: vec![("-name-".to_string(), format!("Reader {n}"))],
+            headers: vec![(
+                "List-Unsubscribe".to_string(),
+                format!("<https://example.com/u/{n}>"),
+            )],
+        }
+    }
+
+    fn personalizations(request: &wiremock::Request) -> Vec<serde_json::Value> {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        body["personalizations"].as_array().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn send_batch_packs_recipients_into_one_request() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let recipients = (0..3).map(batch_recipient).collect::<Vec<_>>();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client
+            .send_batch("Hi -name-", "<p>Hi -name-</p>", "Hi -name-", &recipients)
+            .await;
+
+        assert!(outcomes.iter().all(Result::is_ok));
+        let requests = mock_server.received_requests().await.unwrap();
+        let personalizations = personalizations(&requests[0]);
+        assert_eq!(personalizations.len(), 3);
+        assert_eq!(
+            personalizations[1],
+            serde_json::json!({
+                "to": [{ "email": "reader1@example.com" }],
+                "subject": "Hi -name-",
+                "substitutions": { "-name-": "Reader 1" },
+                "headers": { "List-Unsubscribe": "<https://example.com/u/1>" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_recipients_above_the_provider_limit() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let recipients = (0..super::MAX_PERSONALIZATIONS + 1)
+            .map(batch_recipient)
+            .collect::<Vec<_>>();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client
+            .send_batch("Subject", "<p>Body</p>", "Body", &recipients)
+            .await;
+
+        assert_eq!(outcomes.len(), recipients.len());
+        let requests = mock_server.received_requests().await.unwrap();
+        assert_eq!(
+            personalizations(&requests[0]).len(),
+            super::MAX_PERSONALIZATIONS
+        );
+        assert_eq!(personalizations(&requests[1]).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_recipients_are_reported_and_the_others_are_sent_again() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let recipients = (0..3).map(batch_recipient).collect::<Vec<_>>();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "errors": [{
+                    "message": "Does not contain a valid address.",
+                    "field": "personalizations.1.to.0.email",
+                }]
+            })))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client
+            .send_batch("Subject", "<p>Body</p>", "Body", &recipients)
+            .await;
+
+        assert!(outcomes[0].is_ok());
+        assert!(matches!(
+            &outcomes[1],
+            Err(EmailError::RecipientRejected(message)) if message == "Does not contain a valid address."
+        ));
+        assert!(outcomes[2].is_ok());
+        let requests = mock_server.received_requests().await.unwrap();
+        let retried = personalizations(&requests[1]);
+        assert_eq!(retried.len(), 2);
+        assert_eq!(retried[1]["to"][0]["email"], "reader2@example.com");
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_request_fails_every_recipient() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let recipients = (0..2).map(batch_recipient).collect::<Vec<_>>();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client
+            .send_batch("Subject", "<p>Body</p>", "Body", &recipients)
+            .await;
+
+        for outcome in outcomes {
+            let error = outcome.unwrap_err();
+            assert!(matches!(error, EmailError::BatchFailed { .. }));
+            assert!(error.is_transient());
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_fail_fast_until_retry_after() {
+        let (email_client, mock_server) = get_mock_client().await;
+        let email_client = email_client.with_rate_limiter(RateLimiter::new(100.0, 10));
+        let (to, subject, content) = get_mock_req_data().await;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(202))
+            .mount(&mock_server)
+            .await;
+
+        let error = email_client
+            .send_email(&to, &subject, &content, &content)
+            .await
+            .unwrap_err();
+        assert!(error.is_transient());
+        assert!(error.is_throttle());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(1)));
+
+        let start = Instant::now();
+        let error = email_client
+            .send_email(&to, &subject, &content, &content)
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(error.is_throttle());
+        assert!(matches!(
+            error,
+            EmailError::RateLimited {
+                retry_after: Some(_)
+            }
+        ));
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+
+        actix_web::rt::time::sleep(error.retry_after().unwrap()).await;
+        email_client
+            .send_email(&to, &subject, &content, &content)
+            .await
+            .unwrap();
+    }
+}
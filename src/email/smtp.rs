@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+use super::{build_message, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+
+/// Sends emails to an SMTP relay.
+///
+/// The relay is given as a URL: `smtp://host:587?tls=required` upgrades the connection with
+/// STARTTLS, `smtps://host:465` uses implicit TLS and a bare `smtp://host:25` stays in plain text.
+/// When a username is configured, the client authenticates with `AUTH PLAIN` or `AUTH LOGIN`,
+/// whichever the relay supports.
+#[derive(Debug)]
+pub struct SmtpClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: SubscriberEmail,
+}
+
+impl SmtpClient {
+    pub fn new(
+        relay_url: &str,
+        username: Option<String>,
+        password: String,
+        from: SubscriberEmail,
+    ) -> Result<Self, EmailError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::from_url(relay_url)?
+            .timeout(Some(Duration::from_secs(10)));
+        if let Some(username) = username {
+            builder = builder
+                .credentials(Credentials::new(username, password))
+                .authentication(vec![Mechanism::Plain, Mechanism::Login]);
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpClient {
+    async fn send_email_with_headers(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EmailError> {
+        let message = build_message(&self.from, to, subject, html_content, text_content, headers)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailSender, SmtpClient, SubscriberEmail};
+    use base64::Engine;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A minimal SMTP server handling a single session. It advertises the given `AUTH`
+    /// mechanisms, answers the end of `DATA` with `data_reply` and returns every line it received.
+    async fn smtp_stand_in(
+        auth_mechanisms: &'static str,
+        data_reply: &'static str,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let session = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = Vec::new();
+            let mut in_data = false;
+            let mut login_prompts = 0;
+            writer.write_all(b"220 stand-in ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                received.push(line.clone());
+                let reply = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    format!("{data_reply}\r\n")
+                } else if login_prompts == 1 {
+                    login_prompts = 2;
+                    "334 UGFzc3dvcmQ6\r\n".to_string()
+                } else if login_prompts == 2 {
+                    login_prompts = 0;
+                    "235 Authenticated\r\n".to_string()
+                } else if line.starts_with("EHLO") {
+                    format!("250-stand-in\r\n250-AUTH {auth_mechanisms}\r\n250 8BITMIME\r\n")
+                } else if line == "AUTH LOGIN" {
+                    login_prompts = 1;
+                    "334 VXNlcm5hbWU6\r\n".to_string()
+                } else if line.starts_with("AUTH PLAIN") {
+                    "235 Authenticated\r\n".to_string()
+                } else if line == "DATA" {
+                    in_data = true;
+                    "354 Go ahead\r\n".to_string()
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 OK\r\n".to_string()
+                };
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+            received
+        });
+        (url, session)
+    }
+
+    fn client(url: &str) -> SmtpClient {
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        SmtpClient::new(url, Some("user".into()), "password".into(), from).unwrap()
+    }
+
+    fn to() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_authenticates_with_plain_and_delivers_the_message() {
+        let (url, session) = smtp_stand_in("PLAIN LOGIN", "250 Queued").await;
+
+        client(&url)
+            .send_email_with_headers(
+                &to(),
+                "Our first issue",
+                "<p>Hello!</p>",
+                "Hello!",
+                &[("List-Unsubscribe", "<https://example.com/u>")],
+            )
+            .await
+            .unwrap();
+
+        let received = session.await.unwrap();
+        let credentials = base64::engine::general_purpose::STANDARD.encode("\0user\0password");
+        assert!(received.contains(&format!("AUTH PLAIN {credentials}")));
+        assert!(received.contains(&"MAIL FROM:<newsletter@example.com>".to_string()));
+        assert!(received.contains(&"RCPT TO:<ursula@example.com>".to_string()));
+        assert!(received.contains(&"Subject: Our first issue".to_string()));
+        assert!(received.contains(&"List-Unsubscribe: <https://example.com/u>".to_string()));
+        assert!(received.contains(&"Hello!".to_string()));
+    }
+
+    #[tokio::test]
+    async fn send_email_falls_back_to_login_authentication() {
+        let (url, session) = smtp_stand_in("LOGIN", "250 Queued").await;
+
+        client(&url)
+            .send_email(&to(), "Subject", "<p>Body</p>", "Body")
+            .await
+            .unwrap();
+
+        let received = session.await.unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        let login = received
+            .iter()
+            .position(|line| line == "AUTH LOGIN")
+            .unwrap();
+        assert_eq!(received[login + 1], engine.encode("user"));
+        assert_eq!(received[login + 2], engine.encode("password"));
+    }
+
+    #[tokio::test]
+    async fn a_4xx_reply_is_a_transient_error() {
+        let (url, _session) = smtp_stand_in("PLAIN", "451 Try again later").await;
+
+        let error = client(&url)
+            .send_email(&to(), "Subject", "<p>Body</p>", "Body")
+            .await
+            .unwrap_err();
+        assert!(error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn a_5xx_reply_is_a_permanent_error() {
+        let (url, _session) = smtp_stand_in("PLAIN", "550 No such user").await;
+
+        let error = client(&url)
+            .send_email(&to(), "Subject", "<p>Body</p>", "Body")
+            .await
+            .unwrap_err();
+        assert!(!error.is_transient());
+    }
+}
@@ -0,0 +1,222 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{
+    cookie::{time::Duration, Cookie, CookieJar, Key, SameSite},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+const FLASH_COOKIE_NAME: &str = "_flash";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Success,
+    Error,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Success => "success",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// A one-off message shown on the page the user is redirected to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FlashMessage {
+    level: Level,
+    content: String,
+}
+
+impl FlashMessage {
+    pub fn info(content: impl Into<String>) -> Self {
+        Self::new(Level::Info, content)
+    }
+
+    pub fn success(content: impl Into<String>) -> Self {
+        Self::new(Level::Success, content)
+    }
+
+    pub fn error(content: impl Into<String>) -> Self {
+        Self::new(Level::Error, content)
+    }
+
+    fn new(level: Level, content: impl Into<String>) -> Self {
+        Self {
+            level,
+            content: content.into(),
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Attaches the message to `response`, to be delivered with the next request.
+    pub fn send(self, response: &mut HttpResponse) {
+        let mut extensions = response.extensions_mut();
+        match extensions.get_mut::<OutgoingFlashMessages>() {
+            Some(outgoing) => outgoing.0.push(self),
+            None => {
+                extensions.insert(OutgoingFlashMessages(vec![self]));
+            }
+        }
+    }
+}
+
+struct OutgoingFlashMessages(Vec<FlashMessage>);
+
+/// The flash messages sent along with the previous response.
+#[derive(Clone, Debug, Default)]
+pub struct IncomingFlashMessages(Vec<FlashMessage>);
+
+impl IncomingFlashMessages {
+    pub fn iter(&self) -> impl Iterator<Item = &FlashMessage> {
+        self.0.iter()
+    }
+
+    /// Renders the messages as HTML paragraphs, one per message.
+    pub fn to_html(&self) -> String {
+        self.iter()
+            .map(|m| {
+                format!(
+                    "<p class=\"flash-{}\"><i>{}</i></p>",
+                    m.level.as_str(),
+                    htmlescape::encode_minimal(&m.content)
+                )
+            })
+            .collect()
+    }
+}
+
+impl FromRequest for IncomingFlashMessages {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let messages = req
+            .extensions()
+            .get::<IncomingFlashMessages>()
+            .cloned()
+            .unwrap_or_default();
+        ready(Ok(messages))
+    }
+}
+
+/// Middleware carrying flash messages across a redirect in a signed cookie.
+///
+/// Incoming messages are verified and made available through [`IncomingFlashMessages`]. The
+/// cookie is then cleared, unless the response sends new messages, so each message is shown
+/// exactly once.
+#[derive(Clone)]
+pub struct FlashMessagesFramework {
+    key: Rc<Key>,
+}
+
+impl FlashMessagesFramework {
+    pub fn new(key: Key) -> Self {
+        Self { key: Rc::new(key) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for FlashMessagesFramework
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = FlashMessagesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FlashMessagesMiddleware {
+            service: Rc::new(service),
+            key: self.key.clone(),
+        }))
+    }
+}
+
+pub struct FlashMessagesMiddleware<S> {
+    service: Rc<S>,
+    key: Rc<Key>,
+}
+
+impl<S, B> Service<ServiceRequest> for FlashMessagesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let key = self.key.clone();
+        Box::pin(async move {
+            let has_incoming_cookie = req.cookie(FLASH_COOKIE_NAME).is_some();
+            if let Some(messages) = read_cookie(&req, &key) {
+                req.extensions_mut().insert(IncomingFlashMessages(messages));
+            }
+
+            let mut res = service.call(req).await?;
+
+            let outgoing = res
+                .response_mut()
+                .extensions_mut()
+                .remove::<OutgoingFlashMessages>();
+            match outgoing {
+                Some(OutgoingFlashMessages(messages)) => {
+                    let cookie = write_cookie(&messages, &key)?;
+                    res.response_mut().add_cookie(&cookie)?;
+                }
+                None if has_incoming_cookie => {
+                    let cookie = Cookie::build(FLASH_COOKIE_NAME, "").path("/").finish();
+                    res.response_mut().add_removal_cookie(&cookie)?;
+                }
+                None => {}
+            }
+            Ok(res)
+        })
+    }
+}
+
+fn read_cookie(req: &ServiceRequest, key: &Key) -> Option<Vec<FlashMessage>> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(FLASH_COOKIE_NAME)?);
+    let cookie = jar.signed(key).get(FLASH_COOKIE_NAME)?;
+    serde_json::from_str(cookie.value()).ok()
+}
+
+fn write_cookie(
+    messages: &[FlashMessage],
+    key: &Key,
+) -> Result<Cookie<'static>, serde_json::Error> {
+    let value = serde_json::to_string(messages)?;
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(
+        Cookie::build(FLASH_COOKIE_NAME, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::minutes(5))
+            .finish(),
+    );
+    Ok(jar.get(FLASH_COOKIE_NAME).unwrap().clone())
+}
@@ -0,0 +1,460 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use rand::Rng;
+use sqlx::{PgPool, Postgres, Transaction};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::newsletter_issue::{batch_recipient, get_issue, mark_sent_if_complete};
+use crate::subscriber_links::SubscriberLinks;
+use crate::{EmailError, EmailSender, SubscriberEmail};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// How failed deliveries are retried before being moved to the dead-letter state.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt, after `n_retries` failed ones: exponential in the number of
+    /// retries, capped at `max_delay`, with a random jitter of up to half the delay.
+    pub fn backoff(&self, n_retries: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(n_retries))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Delivers queued newsletter issues until the process stops.
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    retry_policy: RetryPolicy,
+    batch_size: u32,
+    subscriber_links: SubscriberLinks,
+) {
+    loop {
+        match try_execute_task(
+            &pool,
+            email_client.as_ref(),
+            &retry_policy,
+            batch_size,
+            &subscriber_links,
+        )
+        .await
+        {
+            Ok(ExecutionOutcome::EmptyQueue) => sleep(Duration::from_secs(10)).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(error) => {
+                tracing::error!(error.cause_chain = ?error, "Failed to execute a delivery task");
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Dequeues up to `batch_size` due delivery tasks of the same issue and sends their emails
+/// with a single [`EmailSender::send_batch`] call.
+///
+/// The task rows stay locked until they are completed or rescheduled, while `SKIP LOCKED` lets
+/// concurrent workers move on to other tasks instead of waiting on them. Each task gets the
+/// outcome of its own recipient: transient failures are retried according to `retry_policy`;
+/// permanent ones, and tasks that run out of attempts, are dead-lettered.
+#[tracing::instrument(
+    skip_all,
+    fields(newsletter_issue_id = tracing::field::Empty, n_tasks = tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    retry_policy: &RetryPolicy,
+    batch_size: u32,
+    subscriber_links: &SubscriberLinks,
+) -> Result<ExecutionOutcome, Box<dyn std::error::Error>> {
+    let (mut transaction, tasks) = dequeue_tasks(pool, batch_size).await?;
+    let Some(newsletter_issue_id) = tasks.first().map(|task| task.newsletter_issue_id) else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    let span = tracing::Span::current();
+    span.record(
+        "newsletter_issue_id",
+        tracing::field::display(newsletter_issue_id),
+    );
+    span.record("n_tasks", tasks.len());
+
+    let issue = get_issue(pool, newsletter_issue_id)
+        .await?
+        .ok_or("The newsletter issue of a delivery task is missing")?;
+    let mut recipients = Vec::with_capacity(tasks.len());
+    let mut recipient_tasks = Vec::with_capacity(tasks.len());
+    for task in &tasks {
+        match get_recipient(&mut transaction, &task.subscriber_email).await? {
+            Ok((subscriber_id, email)) => {
+                recipients.push(batch_recipient(subscriber_links, subscriber_id, email));
+                recipient_tasks.push(task);
+            }
+            Err(error) => {
+                tracing::warn!(
+                    error.cause_chain = ?error,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a delivery.",
+                );
+                record_delivery(
+                    &mut transaction,
+                    task,
+                    DeliveryStatus::Skipped,
+                    Some(&error),
+                )
+                .await?;
+                delete_task(&mut transaction, task).await?;
+            }
+        }
+    }
+    if !recipients.is_empty() {
+        let issue = issue.render_for_batch();
+        let outcomes = email_client
+            .send_batch(&issue.subject, &issue.html, &issue.text, &recipients)
+            .await;
+        for (task, outcome) in recipient_tasks.into_iter().zip(outcomes) {
+            settle_task(&mut transaction, task, outcome, retry_policy).await?;
+        }
+    }
+    mark_sent_if_complete(&mut transaction, newsletter_issue_id).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Completes a task whose email was sent, or reschedules or dead-letters it when sending
+/// failed.
+#[tracing::instrument(
+    skip_all,
+    fields(subscriber_email = %task.subscriber_email)
+)]
+async fn settle_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    outcome: Result<(), EmailError>,
+    retry_policy: &RetryPolicy,
+) -> Result<(), sqlx::Error> {
+    match outcome {
+        Ok(()) => {
+            record_delivery(transaction, task, DeliveryStatus::Sent, None).await?;
+            delete_task(transaction, task).await?;
+        }
+        Err(error) => {
+            let n_attempts = task.n_retries as u32 + 1;
+            if error.is_transient() && n_attempts < retry_policy.max_attempts {
+                if error.is_throttle() {
+                    tracing::info!(
+                        error.cause_chain = ?error,
+                        n_attempts,
+                        "Rate limited while delivering issue. Retrying later.",
+                    );
+                } else {
+                    tracing::warn!(
+                        error.cause_chain = ?error,
+                        n_attempts,
+                        "Failed to deliver issue to a confirmed subscriber. Retrying later.",
+                    );
+                }
+                // Never sooner than the provider asked for, when it is rate limiting.
+                let delay = retry_policy
+                    .backoff(task.n_retries as u32)
+                    .max(error.retry_after().unwrap_or_default());
+                reschedule_task(transaction, task, delay, &error.to_string()).await?;
+            } else {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    n_attempts,
+                    "Failed to deliver issue to a confirmed subscriber. Dead-lettering.",
+                );
+                let error = error.to_string();
+                dead_letter_task(transaction, task, &error).await?;
+                record_delivery(transaction, task, DeliveryStatus::Failed, Some(&error)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks up who a delivery goes to, or why it should be skipped: the subscriber may have left
+/// since the issue was queued, or their stored address may be invalid.
+async fn get_recipient(
+    transaction: &mut PgTransaction,
+    subscriber_email: &str,
+) -> Result<Result<(Uuid, SubscriberEmail), String>, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        r#"SELECT id, status FROM subscriptions WHERE email = $1"#,
+        subscriber_email,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(match subscriber {
+        Some(subscriber) if subscriber.status == "confirmed" => {
+            SubscriberEmail::parse(subscriber_email.to_string()).map(|email| (subscriber.id, email))
+        }
+        _ => Err("The subscriber is no longer confirmed.".to_string()),
+    })
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+/// The outcome of delivering an issue to one subscriber, as recorded in `issue_deliveries`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Queued,
+    Sent,
+    Failed,
+    Skipped,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Skipped => "skipped",
+        }
+    }
+}
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i32,
+}
+
+/// Locks up to `batch_size` due tasks, all of the same issue so that they can be sent as one
+/// batch.
+#[tracing::instrument(skip(pool))]
+async fn dequeue_tasks(
+    pool: &PgPool,
+    batch_size: u32,
+) -> Result<(PgTransaction, Vec<DeliveryTask>), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_retries
+        FROM issue_delivery_queue
+        WHERE status = 'pending' AND execute_after <= now()
+            AND newsletter_issue_id = (
+                SELECT newsletter_issue_id
+                FROM issue_delivery_queue
+                WHERE status = 'pending' AND execute_after <= now()
+                FOR UPDATE
+                SKIP LOCKED
+                LIMIT 1
+            )
+        ORDER BY subscriber_email
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        i64::from(batch_size.max(1)),
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    Ok((transaction, tasks))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, task, error))]
+async fn reschedule_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    delay: Duration,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET n_retries = n_retries + 1, execute_after = $3, last_error = $4
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        OffsetDateTime::now_utc() + delay,
+        error,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn dead_letter_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET n_retries = n_retries + 1, status = 'dead_letter', last_error = $3
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        error,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(transaction, task, error))]
+async fn record_delivery(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    status: DeliveryStatus,
+    error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_deliveries
+        SET status = $3, error = $4, updated_at = $5
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        status.as_str(),
+        error,
+        OffsetDateTime::now_utc(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// A delivery that ran out of attempts or failed permanently.
+#[derive(serde::Serialize, Debug)]
+pub struct DeadLetter {
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_email: String,
+    pub n_attempts: i32,
+    pub last_error: Option<String>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_dead_letters(pool: &PgPool) -> Result<Vec<DeadLetter>, sqlx::Error> {
+    sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_retries AS n_attempts, last_error
+        FROM issue_delivery_queue
+        WHERE status = 'dead_letter'
+        ORDER BY newsletter_issue_id, subscriber_email
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Puts dead-lettered deliveries back in the queue with a fresh attempt budget, optionally
+/// restricted to one issue and/or one subscriber. Returns how many deliveries were re-driven.
+#[tracing::instrument(skip(pool))]
+pub async fn redrive_dead_letters(
+    pool: &PgPool,
+    newsletter_issue_id: Option<Uuid>,
+    subscriber_email: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let redriven = sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET status = 'pending', n_retries = 0, execute_after = now(), last_error = NULL
+        WHERE status = 'dead_letter'
+            AND ($1::uuid IS NULL OR newsletter_issue_id = $1)
+            AND ($2::text IS NULL OR subscriber_email = $2)
+        RETURNING newsletter_issue_id, subscriber_email
+        "#,
+        newsletter_issue_id,
+        subscriber_email,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    for task in &redriven {
+        sqlx::query!(
+            r#"
+            UPDATE issue_deliveries
+            SET status = $3, error = NULL, updated_at = $4
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+            "#,
+            task.newsletter_issue_id,
+            task.subscriber_email,
+            DeliveryStatus::Queued.as_str(),
+            OffsetDateTime::now_utc(),
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(redriven.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_with_jitter() {
+        let policy = policy();
+        for n_retries in 0..5 {
+            let ceiling = Duration::from_secs(2u64.pow(n_retries));
+            let delay = policy.backoff(n_retries);
+            assert!(delay >= ceiling / 2, "{delay:?} is below {:?}", ceiling / 2);
+            assert!(delay <= ceiling, "{delay:?} is above {ceiling:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = policy();
+        for n_retries in [6, 10, 31, 32, 100] {
+            let delay = policy.backoff(n_retries);
+            assert!(delay >= policy.max_delay / 2);
+            assert!(delay <= policy.max_delay);
+        }
+    }
+}
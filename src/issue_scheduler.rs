@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use sqlx::PgPool;
+
+use crate::newsletter_issue::release_due_issues;
+
+/// Releases scheduled newsletter issues into delivery as they become due, until the process stops.
+pub async fn run_scheduler_until_stopped(pool: PgPool, poll_interval: Duration) {
+    loop {
+        if let Err(error) = release_due_issues(&pool).await {
+            tracing::error!(error.cause_chain = ?error, "Failed to release due newsletter issues");
+        }
+        sleep(poll_interval).await;
+    }
+}
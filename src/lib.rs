@@ -1,24 +1,102 @@
+mod authentication;
 mod domain;
 mod email;
+mod flash;
+mod idempotency;
+mod issue_delivery_worker;
+mod issue_scheduler;
+mod newsletter_issue;
 mod routes;
+mod session;
 mod settings;
+mod subscriber_links;
 mod telemetry;
+mod token_cleanup;
 
+pub use authentication::seed_admin_user;
 pub use domain::SubscriberEmail;
-pub use email::EmailClient;
-pub use routes::ApplicationBaseUrl;
+pub use email::{
+    build_email_sender, BatchRecipient, BreakerPolicy, CircuitBreaker, EmailError, EmailSender,
+    FailoverClient, OutboxClient, OutboxEmail, PostmarkClient, RateLimiter, SendGridClient,
+    SmtpClient,
+};
+pub use flash::{FlashMessage, FlashMessagesFramework, IncomingFlashMessages, Level};
+pub use issue_delivery_worker::{
+    run_worker_until_stopped, try_execute_task, ExecutionOutcome, RetryPolicy,
+};
+pub use issue_scheduler::run_scheduler_until_stopped;
+pub use newsletter_issue::release_due_issues;
+pub use routes::{ApplicationBaseUrl, NewsletterTopics};
+pub use session::{session_middleware, PgSessionStore, TypedSession};
 pub use settings::*;
+pub use subscriber_links::{LinkPurpose, SubscriberLinks};
 pub use telemetry::init_tracing;
+pub use token_cleanup::{delete_stale_tokens, run_token_cleanup_until_stopped};
 
-use actix_web::web::{get, post, ServiceConfig};
+use actix_web::{
+    middleware::from_fn,
+    web::{delete, get, post, put, scope, ServiceConfig},
+};
+use authentication::reject_anonymous_users;
 use routes::*;
 
 pub fn app_config(cfg: &mut ServiceConfig) {
     cfg.route("/health_check", get().to(health_check));
     cfg.route("/subscriptions", post().to(subscribe));
     cfg.route("/subscriptions/confirm", get().to(confirm_subscription));
+    cfg.route(
+        "/subscriptions/confirm/resend",
+        post().to(resend_confirmation),
+    );
+    cfg.route("/subscriptions/preferences", get().to(preferences_page));
+    cfg.route("/subscriptions/preferences", post().to(update_preferences));
+    cfg.route("/subscriptions/unsubscribe", get().to(unsubscribe_page));
+    cfg.route("/subscriptions/unsubscribe", post().to(unsubscribe));
     cfg.route("/newsletters", post().to(post_newsletter));
     cfg.route("/", get().to(home));
     cfg.route("/login", get().to(login_page));
     cfg.route("/login", post().to(login));
+    cfg.service(
+        scope("/admin")
+            .wrap(from_fn(reject_anonymous_users))
+            .route("/dashboard", get().to(admin_dashboard))
+            .route("/deliveries/dead_letters", get().to(list_dead_letters))
+            .route("/deliveries/dead_letters/redrive", post().to(redrive))
+            .route("/newsletters", get().to(publish_newsletter_form))
+            .route("/newsletters", post().to(publish_newsletter))
+            .route("/newsletters/drafts", get().to(list_drafts))
+            .route("/newsletters/drafts", post().to(create_draft))
+            .route(
+                "/newsletters/{newsletter_issue_id}",
+                get().to(newsletter_issue_report),
+            )
+            .route("/newsletters/{newsletter_issue_id}", put().to(edit_draft))
+            .route(
+                "/newsletters/{newsletter_issue_id}",
+                delete().to(discard_draft),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/preview",
+                get().to(preview_issue),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/test",
+                post().to(send_test_issue),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/publish",
+                post().to(publish_draft),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/cancel",
+                post().to(cancel_newsletter_issue),
+            )
+            .route(
+                "/newsletters/{newsletter_issue_id}/reschedule",
+                post().to(reschedule_newsletter_issue),
+            )
+            .route("/password", get().to(change_password_form))
+            .route("/password", post().to(change_password))
+            .route("/logout", post().to(log_out)),
+    );
 }
@@ -1,7 +1,12 @@
-use actix_web::{web, App, HttpServer};
+use actix_web::{cookie::time::Duration, cookie::Key, web, App, HttpServer};
 use sqlx::PgPool;
 use tracing_actix_web::TracingLogger;
-use zero2prod::{app_config, get_settings, ApplicationBaseUrl, EmailClient, SubscriberEmail};
+use zero2prod::{
+    app_config, build_email_sender, get_settings, run_scheduler_until_stopped,
+    run_token_cleanup_until_stopped, run_worker_until_stopped, seed_admin_user, session_middleware,
+    ApplicationBaseUrl, FlashMessagesFramework, NewsletterTopics, PgSessionStore, RetryPolicy,
+    SubscriberLinks,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -11,24 +16,67 @@ async fn main() -> std::io::Result<()> {
     let address = format!("{}:{}", settings.app_host, settings.app_port);
 
     let db_pool = PgPool::connect_lazy(&settings.database_url).unwrap();
+    let session_store = PgSessionStore::new(
+        db_pool.clone(),
+        Duration::seconds(settings.session_absolute_timeout_secs),
+    );
+    if let Some(password_hash) = &settings.admin_password_hash {
+        seed_admin_user(password_hash, &db_pool)
+            .await
+            .expect("Failed to seed the admin user");
+    }
     let db_pool = web::Data::new(db_pool);
 
-    let email_client = EmailClient::new(
-        settings.email_base_url,
-        settings.email_auth_token,
-        SubscriberEmail::parse(settings.email_sender).unwrap(),
-    );
-    let email_client = web::Data::new(email_client);
+    let secret_key = Key::from(settings.hmac_secret.as_bytes());
+    let session_idle_ttl = Duration::seconds(settings.session_idle_timeout_secs);
+
+    let email_client = build_email_sender(&settings).expect("Failed to set up the email sender");
+    let subscriber_links =
+        SubscriberLinks::new(settings.app_base_url.clone(), &settings.hmac_secret);
+
+    let retry_policy = RetryPolicy {
+        max_attempts: settings.issue_delivery_max_attempts,
+        base_delay: std::time::Duration::from_secs(settings.issue_delivery_base_backoff_secs),
+        max_delay: std::time::Duration::from_secs(settings.issue_delivery_max_backoff_secs),
+    };
+    for _ in 0..settings.issue_delivery_workers {
+        actix_web::rt::spawn(run_worker_until_stopped(
+            db_pool.get_ref().clone(),
+            email_client.clone(),
+            retry_policy.clone(),
+            settings.issue_delivery_batch_size,
+            subscriber_links.clone(),
+        ));
+    }
+    actix_web::rt::spawn(run_scheduler_until_stopped(
+        db_pool.get_ref().clone(),
+        std::time::Duration::from_secs(settings.issue_scheduler_interval_secs),
+    ));
+    actix_web::rt::spawn(run_token_cleanup_until_stopped(
+        db_pool.get_ref().clone(),
+        std::time::Duration::from_secs(settings.token_cleanup_interval_secs),
+    ));
+    let email_client = web::Data::from(email_client);
 
+    let subscriber_links = web::Data::new(subscriber_links);
     let app_base_url = web::Data::new(ApplicationBaseUrl(settings.app_base_url));
+    let newsletter_topics = web::Data::new(NewsletterTopics(settings.newsletter_topics));
 
     HttpServer::new(move || {
         App::new()
+            .wrap(FlashMessagesFramework::new(secret_key.clone()))
+            .wrap(session_middleware(
+                session_store.clone(),
+                secret_key.clone(),
+                session_idle_ttl,
+            ))
             .wrap(TracingLogger::default())
             .configure(app_config)
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
             .app_data(app_base_url.clone())
+            .app_data(subscriber_links.clone())
+            .app_data(newsletter_topics.clone())
     })
     .bind(address)?
     .run()
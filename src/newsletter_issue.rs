@@ -0,0 +1,638 @@
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::issue_delivery_worker::DeliveryStatus;
+use crate::subscriber_links::{LinkPurpose, SubscriberLinks};
+use crate::{BatchRecipient, SubscriberEmail};
+
+/// Where a newsletter issue is in its lifecycle.
+///
+/// Drafts can be edited freely. Publishing moves a draft to `scheduled`, or straight to `sending`
+/// when it is due, and the scheduler later moves scheduled issues to `sending`. An issue is `sent`
+/// once none of its deliveries are pending anymore. Drafts and scheduled issues can be
+/// `cancelled`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum IssueStatus {
+    Draft,
+    Scheduled,
+    Sending,
+    Sent,
+    Cancelled,
+}
+
+impl IssueStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueStatus::Draft => "draft",
+            IssueStatus::Scheduled => "scheduled",
+            IssueStatus::Sending => "sending",
+            IssueStatus::Sent => "sent",
+            IssueStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::fmt::Display for IssueStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewsletterIssue {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+    /// Only subscribers following this topic, or no topic in particular, receive the issue.
+    pub topic: Option<String>,
+    pub status: IssueStatus,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub scheduled_for: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub published_at: Option<OffsetDateTime>,
+}
+
+/// An issue as it lands in a subscriber's inbox.
+#[derive(Debug)]
+pub struct RenderedIssue {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    unsubscribe_link: String,
+}
+
+impl RenderedIssue {
+    /// The RFC 8058 one-click unsubscribe headers.
+    pub fn headers(&self) -> [(&'static str, String); 2] {
+        unsubscribe_headers(&self.unsubscribe_link)
+    }
+}
+
+/// An issue rendered once for a whole batch of subscribers.
+#[derive(Debug)]
+pub struct BatchIssue {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+fn unsubscribe_headers(unsubscribe_link: &str) -> [(&'static str, String); 2] {
+    [
+        ("List-Unsubscribe", format!("<{unsubscribe_link}>")),
+        (
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click".to_string(),
+        ),
+    ]
+}
+
+/// Placeholders for the links of each recipient of an issue sent in a batch. The HTML part has
+/// its own, since links are escaped there.
+const PREFERENCES_LINK: &str = "-preferences_link-";
+const PREFERENCES_LINK_HTML: &str = "-preferences_link_html-";
+const UNSUBSCRIBE_LINK: &str = "-unsubscribe_link-";
+const UNSUBSCRIBE_LINK_HTML: &str = "-unsubscribe_link_html-";
+
+impl NewsletterIssue {
+    /// Renders the email sent to a subscriber, with a footer linking to their preferences and
+    /// to unsubscribing. Previews go through here too, so that they show exactly what recipients
+    /// get.
+    pub fn render(&self, links: &SubscriberLinks, subscriber_id: Uuid) -> RenderedIssue {
+        let preferences_link = links.link(LinkPurpose::Preferences, subscriber_id);
+        let unsubscribe_link = links.link(LinkPurpose::Unsubscribe, subscriber_id);
+        RenderedIssue {
+            subject: self.title.clone(),
+            html: self.html_with_footer(
+                &htmlescape::encode_minimal(&preferences_link),
+                &htmlescape::encode_minimal(&unsubscribe_link),
+            ),
+            text: self.text_with_footer(&preferences_link, &unsubscribe_link),
+            unsubscribe_link,
+        }
+    }
+
+    /// Renders the email sent to a batch of subscribers, with placeholders for the links that
+    /// [`batch_recipient`] fills in for each of them. Filled in, it is the email that
+    /// [`NewsletterIssue::render`] renders for that subscriber.
+    pub fn render_for_batch(&self) -> BatchIssue {
+        BatchIssue {
+            subject: self.title.clone(),
+            html: self.html_with_footer(PREFERENCES_LINK_HTML, UNSUBSCRIBE_LINK_HTML),
+            text: self.text_with_footer(PREFERENCES_LINK, UNSUBSCRIBE_LINK),
+        }
+    }
+
+    fn html_with_footer(&self, preferences_link: &str, unsubscribe_link: &str) -> String {
+        format!(
+            "{}\n<p><a href=\"{preferences_link}\">Manage your preferences</a> | <a href=\"{unsubscribe_link}\">Unsubscribe</a></p>",
+            self.html_content,
+        )
+    }
+
+    fn text_with_footer(&self, preferences_link: &str, unsubscribe_link: &str) -> String {
+        format!(
+            "{}\n\nManage your preferences: {preferences_link}\nUnsubscribe: {unsubscribe_link}",
+            self.text_content
+        )
+    }
+}
+
+/// A subscriber receiving an issue rendered with [`NewsletterIssue::render_for_batch`], with
+/// their own links and unsubscribe headers.
+pub fn batch_recipient(
+    links: &SubscriberLinks,
+    subscriber_id: Uuid,
+    email: SubscriberEmail,
+) -> BatchRecipient {
+    let preferences_link = links.link(LinkPurpose::Preferences, subscriber_id);
+    let unsubscribe_link = links.link(LinkPurpose::Unsubscribe, subscriber_id);
+    BatchRecipient {
+        email,
+        headers: unsubscribe_headers(&unsubscribe_link)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        substitutions: vec![
+            (
+                PREFERENCES_LINK_HTML.to_string(),
+                htmlescape::encode_minimal(&preferences_link),
+            ),
+            (
+                UNSUBSCRIBE_LINK_HTML.to_string(),
+                htmlescape::encode_minimal(&unsubscribe_link),
+            ),
+            (PREFERENCES_LINK.to_string(), preferences_link),
+            (UNSUBSCRIBE_LINK.to_string(), unsubscribe_link),
+        ],
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum IssueError {
+    #[error("The newsletter issue does not exist.")]
+    NotFound,
+    #[error("The newsletter issue is {0}: it cannot be {1}.")]
+    InvalidTransition(IssueStatus, &'static str),
+    #[error(transparent)]
+    UnexpectedError(#[from] Box<dyn std::error::Error>),
+}
+
+impl std::fmt::Debug for IssueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{e}\n")?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{cause}")?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+impl From<sqlx::Error> for IssueError {
+    fn from(e: sqlx::Error) -> Self {
+        IssueError::UnexpectedError(e.into())
+    }
+}
+
+#[tracing::instrument(skip(executor))]
+pub async fn get_issue<'a>(
+    executor: impl sqlx::PgExecutor<'a>,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            topic,
+            status as "status: _",
+            scheduled_for,
+            created_at,
+            updated_at,
+            published_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn list_issues(
+    pool: &PgPool,
+    status: IssueStatus,
+) -> Result<Vec<NewsletterIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            topic,
+            status as "status: _",
+            scheduled_for,
+            created_at,
+            updated_at,
+            published_at
+        FROM newsletter_issues
+        WHERE status = $1
+        ORDER BY updated_at DESC
+        "#,
+        status.as_str(),
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(skip(transaction, text_content, html_content))]
+pub async fn insert_draft(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    topic: Option<&str>,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let now = OffsetDateTime::now_utc();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            topic,
+            status,
+            created_at,
+            updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        topic,
+        IssueStatus::Draft.as_str(),
+        now,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
+
+#[tracing::instrument(skip(pool, text_content, html_content))]
+pub async fn update_draft(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    topic: Option<&str>,
+) -> Result<(), IssueError> {
+    let mut transaction = pool.begin().await?;
+    lock_issue(
+        &mut transaction,
+        newsletter_issue_id,
+        &[IssueStatus::Draft],
+        "edited",
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET title = $2, text_content = $3, html_content = $4, topic = $5, updated_at = $6
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        topic,
+        OffsetDateTime::now_utc(),
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn delete_draft(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<(), IssueError> {
+    let mut transaction = pool.begin().await?;
+    lock_issue(
+        &mut transaction,
+        newsletter_issue_id,
+        &[IssueStatus::Draft],
+        "deleted",
+    )
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Creates an issue and publishes it straight away, see [`publish_issue`].
+#[tracing::instrument(skip(transaction, text_content, html_content))]
+pub async fn create_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    topic: Option<&str>,
+    scheduled_for: Option<OffsetDateTime>,
+) -> Result<(Uuid, IssueStatus), IssueError> {
+    let newsletter_issue_id =
+        insert_draft(transaction, title, text_content, html_content, topic).await?;
+    let status = publish_issue(transaction, newsletter_issue_id, scheduled_for).await?;
+    Ok((newsletter_issue_id, status))
+}
+
+/// Publishes a draft: it is scheduled if `scheduled_for` is in the future, and its deliveries are
+/// queued right away otherwise.
+#[tracing::instrument(skip(transaction))]
+pub async fn publish_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    scheduled_for: Option<OffsetDateTime>,
+) -> Result<IssueStatus, IssueError> {
+    lock_issue(
+        transaction,
+        newsletter_issue_id,
+        &[IssueStatus::Draft],
+        "published",
+    )
+    .await?;
+    let now = OffsetDateTime::now_utc();
+    let status = match scheduled_for {
+        Some(scheduled_for) if scheduled_for > now => IssueStatus::Scheduled,
+        _ => IssueStatus::Sending,
+    };
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, scheduled_for = $3, published_at = $4, updated_at = $4
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        status.as_str(),
+        scheduled_for,
+        now,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if status == IssueStatus::Sending {
+        start_sending(transaction, newsletter_issue_id).await?;
+    }
+    Ok(status)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn cancel_issue(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<(), IssueError> {
+    let mut transaction = pool.begin().await?;
+    lock_issue(
+        &mut transaction,
+        newsletter_issue_id,
+        &[IssueStatus::Draft, IssueStatus::Scheduled],
+        "cancelled",
+    )
+    .await?;
+    set_status(
+        &mut transaction,
+        newsletter_issue_id,
+        IssueStatus::Cancelled,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn reschedule_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    scheduled_for: OffsetDateTime,
+) -> Result<(), IssueError> {
+    let mut transaction = pool.begin().await?;
+    lock_issue(
+        &mut transaction,
+        newsletter_issue_id,
+        &[IssueStatus::Scheduled],
+        "rescheduled",
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET scheduled_for = $2, updated_at = $3
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        scheduled_for,
+        OffsetDateTime::now_utc(),
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Moves every scheduled issue whose time has come to the sending state and queues its
+/// deliveries. Returns how many issues were released.
+///
+/// Due issues are locked with `SKIP LOCKED`, so several schedulers can run side by side, and a
+/// concurrent cancellation either happens before the release or is rejected.
+#[tracing::instrument(skip_all)]
+pub async fn release_due_issues(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let due_issues = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM newsletter_issues
+        WHERE status = $1 AND scheduled_for <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        "#,
+        IssueStatus::Scheduled.as_str(),
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    for issue in &due_issues {
+        set_status(
+            &mut transaction,
+            issue.newsletter_issue_id,
+            IssueStatus::Sending,
+        )
+        .await?;
+        start_sending(&mut transaction, issue.newsletter_issue_id).await?;
+        tracing::info!(
+            newsletter_issue_id = %issue.newsletter_issue_id,
+            "Released a scheduled newsletter issue"
+        );
+    }
+    transaction.commit().await?;
+    Ok(due_issues.len())
+}
+
+/// Marks a sending issue as sent if none of its deliveries are pending anymore.
+#[tracing::instrument(skip(transaction))]
+pub async fn mark_sent_if_complete(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, updated_at = $4
+        WHERE newsletter_issue_id = $1
+            AND status = $3
+            AND NOT EXISTS (
+                SELECT 1
+                FROM issue_delivery_queue
+                WHERE newsletter_issue_id = $1 AND status = 'pending'
+            )
+        "#,
+        newsletter_issue_id,
+        IssueStatus::Sent.as_str(),
+        IssueStatus::Sending.as_str(),
+        OffsetDateTime::now_utc(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+async fn start_sending(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    enqueue_delivery_tasks(transaction, newsletter_issue_id).await?;
+    // An issue without any confirmed subscriber is done already.
+    mark_sent_if_complete(transaction, newsletter_issue_id).await
+}
+
+/// Queues one delivery task per confirmed subscriber following the issue's topic, to be picked up
+/// by the delivery workers, and records each delivery as queued.
+///
+/// Subscribers who follow no topic in particular get every issue. Those who asked for a daily or
+/// weekly digest have their delivery held until the start of the next day or week.
+#[tracing::instrument(skip(transaction))]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, execute_after)
+        SELECT
+            issue.newsletter_issue_id,
+            subscriptions.email,
+            CASE subscriptions.digest_frequency
+                WHEN 'daily' THEN date_trunc('day', now()) + interval '1 day'
+                WHEN 'weekly' THEN date_trunc('week', now()) + interval '1 week'
+                ELSE now()
+            END
+        FROM subscriptions
+        JOIN newsletter_issues issue ON issue.newsletter_issue_id = $1
+        WHERE subscriptions.status = 'confirmed'
+            AND (
+                issue.topic IS NULL
+                OR cardinality(subscriptions.topics) = 0
+                OR issue.topic = ANY(subscriptions.topics)
+            )
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, status, updated_at)
+        SELECT newsletter_issue_id, subscriber_email, $2, $3
+        FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        DeliveryStatus::Queued.as_str(),
+        OffsetDateTime::now_utc(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Locks the issue row for the rest of the transaction, checking that its current status allows
+/// the requested change.
+async fn lock_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    allowed: &[IssueStatus],
+    change: &'static str,
+) -> Result<IssueStatus, IssueError> {
+    let status = sqlx::query!(
+        r#"
+        SELECT status as "status: IssueStatus"
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        FOR UPDATE
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(transaction)
+    .await?
+    .ok_or(IssueError::NotFound)?
+    .status;
+    if !allowed.contains(&status) {
+        return Err(IssueError::InvalidTransition(status, change));
+    }
+    Ok(status)
+}
+
+async fn set_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    status: IssueStatus,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, updated_at = $3
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        status.as_str(),
+        OffsetDateTime::now_utc(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
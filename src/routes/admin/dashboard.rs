@@ -0,0 +1,23 @@
+use actix_web::{error::ErrorInternalServerError, http::header::ContentType, web, HttpResponse};
+use sqlx::PgPool;
+
+use crate::authentication::{get_username, UserId};
+use crate::IncomingFlashMessages;
+
+#[tracing::instrument(skip(pool, flash_messages), fields(user_id = %*user_id))]
+pub async fn admin_dashboard(
+    user_id: web::ReqData<UserId>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(user_id.0, &pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("dashboard.html"),
+            flash_html = flash_messages.to_html(),
+            username = htmlescape::encode_minimal(&username)
+        )))
+}
@@ -0,0 +1,41 @@
+use actix_web::{error::ErrorInternalServerError, web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::optional_json;
+use crate::issue_delivery_worker::{get_dead_letters, redrive_dead_letters};
+
+#[tracing::instrument(skip(pool))]
+pub async fn list_dead_letters(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let dead_letters = get_dead_letters(&pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RedriveFilter {
+    newsletter_issue_id: Option<Uuid>,
+    subscriber_email: Option<String>,
+}
+
+/// Re-drives the dead letters matching the optional filter, or all of them with an empty body. A
+/// filter with unknown fields is rejected rather than taken for no filter at all.
+#[tracing::instrument(skip(body, pool), fields(filter = tracing::field::Empty))]
+pub async fn redrive(
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filter: RedriveFilter = optional_json(&body)?;
+    tracing::Span::current().record("filter", tracing::field::debug(&filter));
+    let n_redriven = redrive_dead_letters(
+        &pool,
+        filter.newsletter_issue_id,
+        filter.subscriber_email.as_deref(),
+    )
+    .await
+    .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "redriven": n_redriven })))
+}
@@ -0,0 +1,273 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorNotFound},
+    http::header::ContentType,
+    web, HttpResponse,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use super::optional_json;
+use crate::newsletter_issue::{
+    cancel_issue, delete_draft, get_issue, insert_draft, list_issues, publish_issue,
+    reschedule_issue, update_draft, IssueError, IssueStatus,
+};
+use crate::{EmailSender, NewsletterTopics, SubscriberEmail, SubscriberLinks};
+
+/// Previews and test copies are not addressed to a subscriber: their links are well-formed but
+/// lead nowhere.
+const SAMPLE_SUBSCRIBER_ID: Uuid = Uuid::nil();
+
+fn issue_error(e: IssueError) -> actix_web::Error {
+    match e {
+        IssueError::NotFound => ErrorNotFound(e),
+        IssueError::InvalidTransition(..) => ErrorConflict(e),
+        IssueError::UnexpectedError(_) => ErrorInternalServerError(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DraftContent {
+    title: String,
+    text_content: String,
+    html_content: String,
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn list_drafts(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let drafts = list_issues(&pool, IssueStatus::Draft)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(drafts))
+}
+
+#[tracing::instrument(skip(body, pool, topics))]
+pub async fn create_draft(
+    body: web::Json<DraftContent>,
+    pool: web::Data<PgPool>,
+    topics: web::Data<NewsletterTopics>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let topic = topics
+        .parse_issue_topic(body.topic.as_deref())
+        .map_err(ErrorBadRequest)?;
+    let mut transaction = pool.begin().await.map_err(ErrorInternalServerError)?;
+    let newsletter_issue_id = insert_draft(
+        &mut transaction,
+        &body.title,
+        &body.text_content,
+        &body.html_content,
+        topic.as_deref(),
+    )
+    .await
+    .map_err(ErrorInternalServerError)?;
+    let draft = get_issue(&mut transaction, newsletter_issue_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Created().json(draft))
+}
+
+#[tracing::instrument(skip(body, pool, topics))]
+pub async fn edit_draft(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<DraftContent>,
+    pool: web::Data<PgPool>,
+    topics: web::Data<NewsletterTopics>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let topic = topics
+        .parse_issue_topic(body.topic.as_deref())
+        .map_err(ErrorBadRequest)?;
+    update_draft(
+        &pool,
+        newsletter_issue_id,
+        &body.title,
+        &body.text_content,
+        &body.html_content,
+        topic.as_deref(),
+    )
+    .await
+    .map_err(issue_error)?;
+    let draft = get_issue(pool.get_ref(), newsletter_issue_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn discard_draft(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    delete_draft(&pool, newsletter_issue_id.into_inner())
+        .await
+        .map_err(issue_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    Html,
+    Text,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreviewQuery {
+    format: Option<PreviewFormat>,
+}
+
+/// Shows an issue the way subscribers will receive it, as HTML or, with `?format=text`, as the
+/// plain text alternative.
+#[tracing::instrument(skip(pool, subscriber_links))]
+pub async fn preview_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    query: web::Query<PreviewQuery>,
+    pool: web::Data<PgPool>,
+    subscriber_links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue = get_issue(pool.get_ref(), newsletter_issue_id.into_inner())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| issue_error(IssueError::NotFound))?
+        .render(&subscriber_links, SAMPLE_SUBSCRIBER_ID);
+    Ok(match query.format {
+        Some(PreviewFormat::Html) | None => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(issue.html),
+        Some(PreviewFormat::Text) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(issue.text),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TestSend {
+    addresses: Vec<String>,
+}
+
+const MAX_TEST_ADDRESSES: usize = 10;
+
+/// Sends a copy of an issue, with a `[TEST]` subject prefix, to the given addresses only. The
+/// issue and the delivery records of its subscribers are left untouched.
+#[tracing::instrument(skip(pool, email_client, subscriber_links))]
+pub async fn send_test_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<TestSend>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    subscriber_links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let TestSend { addresses } = body.into_inner();
+    if addresses.is_empty() || addresses.len() > MAX_TEST_ADDRESSES {
+        return Err(ErrorBadRequest(format!(
+            "Between 1 and {MAX_TEST_ADDRESSES} addresses must be provided."
+        )));
+    }
+    let addresses = addresses
+        .into_iter()
+        .map(SubscriberEmail::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ErrorBadRequest)?;
+
+    let issue = get_issue(pool.get_ref(), newsletter_issue_id.into_inner())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| issue_error(IssueError::NotFound))?
+        .render(&subscriber_links, SAMPLE_SUBSCRIBER_ID);
+    let subject = format!("[TEST] {}", issue.subject);
+    let headers = issue.headers();
+    let headers = headers
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::with_capacity(addresses.len());
+    for address in &addresses {
+        let outcome = email_client
+            .send_email_with_headers(address, &subject, &issue.html, &issue.text, &headers)
+            .await;
+        if let Err(error) = &outcome {
+            tracing::warn!(
+                error.cause_chain = ?error,
+                address = %address,
+                "Failed to send a test copy of a newsletter issue",
+            );
+        }
+        results.push(serde_json::json!({
+            "address": address.as_ref(),
+            "sent": outcome.is_ok(),
+            "error": outcome.err().map(|e| e.to_string()),
+        }));
+    }
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Publish {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    scheduled_for: Option<OffsetDateTime>,
+}
+
+/// Publishes a draft, either right away or at `scheduled_for`. A body that cannot be parsed, or
+/// that has unknown fields, is rejected, so that a mistyped date or field name never publishes the
+/// issue right away.
+#[tracing::instrument(skip(body, pool))]
+pub async fn publish_draft(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let body: Publish = optional_json(&body)?;
+    let mut transaction = pool.begin().await.map_err(ErrorInternalServerError)?;
+    publish_issue(&mut transaction, newsletter_issue_id, body.scheduled_for)
+        .await
+        .map_err(issue_error)?;
+    let issue = get_issue(&mut transaction, newsletter_issue_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Accepted().json(issue))
+}
+
+/// Cancels a draft, or an issue that is still waiting for its scheduled time.
+#[tracing::instrument(skip(pool))]
+pub async fn cancel_newsletter_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    cancel_issue(&pool, newsletter_issue_id.into_inner())
+        .await
+        .map_err(issue_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Reschedule {
+    #[serde(with = "time::serde::rfc3339")]
+    scheduled_for: OffsetDateTime,
+}
+
+/// Moves the send time of an issue that is still waiting for its scheduled time.
+#[tracing::instrument(skip(pool))]
+pub async fn reschedule_newsletter_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<Reschedule>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    reschedule_issue(&pool, newsletter_issue_id.into_inner(), body.scheduled_for)
+        .await
+        .map_err(issue_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,13 @@
+use actix_web::{http::header::LOCATION, HttpResponse};
+
+use crate::{FlashMessage, TypedSession};
+
+#[tracing::instrument(skip(session))]
+pub async fn log_out(session: TypedSession) -> HttpResponse {
+    session.log_out();
+    let mut response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish();
+    FlashMessage::info("You have successfully logged out.").send(&mut response);
+    response
+}
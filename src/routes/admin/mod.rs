@@ -0,0 +1,25 @@
+mod dashboard;
+mod deliveries;
+mod issues;
+mod logout;
+mod newsletters;
+mod password;
+
+pub use dashboard::*;
+pub use deliveries::*;
+pub use issues::*;
+pub use logout::*;
+pub use newsletters::*;
+pub use password::*;
+
+/// Parses an optional JSON body: an empty body stands for the default value, while a malformed
+/// one is rejected instead of being mistaken for no body at all.
+fn optional_json<T>(body: &[u8]) -> Result<T, actix_web::Error>
+where
+    T: serde::de::DeserializeOwned + Default,
+{
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(actix_web::error::ErrorBadRequest)
+}
@@ -0,0 +1,286 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
+    http::header::{ContentType, LOCATION},
+    web, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::authentication::UserId;
+use crate::domain::LocalDateTime;
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::newsletter_issue::create_issue;
+use crate::{FlashMessage, IncomingFlashMessages, NewsletterTopics};
+
+pub async fn publish_newsletter_form(
+    flash_messages: IncomingFlashMessages,
+    topics: web::Data<NewsletterTopics>,
+) -> HttpResponse {
+    let topics_html: String = topics
+        .0
+        .iter()
+        .map(|topic| format!("<option value=\"{topic}\">{topic}</option>"))
+        .collect();
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("newsletters.html"),
+            flash_html = flash_messages.to_html(),
+            topics_html = topics_html,
+            idempotency_key = Uuid::new_v4()
+        ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FormData {
+    title: String,
+    html_content: String,
+    text_content: String,
+    idempotency_key: String,
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    scheduled_for: String,
+    #[serde(default)]
+    timezone: String,
+}
+
+#[tracing::instrument(skip(form, pool, topics), fields(user_id = %*user_id))]
+pub async fn publish_newsletter(
+    form: web::Form<FormData>,
+    user_id: web::ReqData<UserId>,
+    pool: web::Data<PgPool>,
+    topics: web::Data<NewsletterTopics>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner().0;
+    let FormData {
+        title,
+        html_content,
+        text_content,
+        idempotency_key,
+        topic,
+        scheduled_for,
+        timezone,
+    } = form.0;
+    let idempotency_key = match IdempotencyKey::try_from(idempotency_key) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(e) => return Ok(redirect_to_form(FlashMessage::error(e))),
+    };
+    let topic = match topics.parse_issue_topic(Some(&topic)) {
+        Ok(topic) => topic,
+        Err(e) => return Ok(redirect_to_form(FlashMessage::error(e))),
+    };
+    let scheduled_for = match scheduled_for.trim() {
+        "" => None,
+        scheduled_for => match LocalDateTime::parse(scheduled_for, timezone.trim()) {
+            Ok(scheduled_for) => Some(scheduled_for.into_inner()),
+            Err(e) => return Ok(redirect_to_form(FlashMessage::error(e))),
+        },
+    };
+
+    let mut transaction = match try_processing(&pool, &idempotency_key, user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(mut saved_response) => {
+            // The flash message is not part of the saved response: send it again.
+            success_message(scheduled_for).send(&mut saved_response);
+            return Ok(saved_response);
+        }
+    };
+
+    create_issue(
+        &mut transaction,
+        &title,
+        &text_content,
+        &html_content,
+        topic.as_deref(),
+        scheduled_for,
+    )
+    .await
+    .map_err(ErrorInternalServerError)?;
+
+    let response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/newsletters"))
+        .finish();
+    let mut response = save_response(transaction, &idempotency_key, user_id, response)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    success_message(scheduled_for).send(&mut response);
+    Ok(response)
+}
+
+fn success_message(scheduled_for: Option<OffsetDateTime>) -> FlashMessage {
+    match scheduled_for {
+        Some(scheduled_for) if scheduled_for > OffsetDateTime::now_utc() => {
+            FlashMessage::success(format!(
+                "The newsletter issue has been scheduled for {}.",
+                format_timestamp(scheduled_for)
+            ))
+        }
+        _ => FlashMessage::success(
+            "The newsletter issue has been accepted - emails will go out shortly.",
+        ),
+    }
+}
+
+fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    timestamp
+        .format(&Rfc3339)
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+fn redirect_to_form(message: FlashMessage) -> HttpResponse {
+    let mut response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/newsletters"))
+        .finish();
+    message.send(&mut response);
+    response
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Pagination {
+    #[serde(default = "Pagination::default_page")]
+    page: i64,
+    #[serde(default = "Pagination::default_page_size")]
+    page_size: i64,
+}
+
+impl Pagination {
+    const MAX_PAGE_SIZE: i64 = 100;
+
+    fn default_page() -> i64 {
+        1
+    }
+
+    fn default_page_size() -> i64 {
+        50
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+struct DeliveryCounts {
+    queued: i64,
+    sent: i64,
+    failed: i64,
+    skipped: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct FailedDelivery {
+    subscriber_email: String,
+    status: String,
+    error: Option<String>,
+}
+
+/// Reports how an issue's deliveries went: counts per status, and a page of the deliveries that
+/// failed or were skipped.
+#[tracing::instrument(skip(pool))]
+pub async fn newsletter_issue_report(
+    newsletter_issue_id: web::Path<Uuid>,
+    pagination: web::Query<Pagination>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    if pagination.page < 1 || !(1..=Pagination::MAX_PAGE_SIZE).contains(&pagination.page_size) {
+        return Err(ErrorBadRequest(format!(
+            "page must be at least 1 and page_size between 1 and {}",
+            Pagination::MAX_PAGE_SIZE
+        )));
+    }
+
+    let Some(issue) = sqlx::query!(
+        r#"
+        SELECT title, status, scheduled_for
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(ErrorInternalServerError)?
+    else {
+        return Err(ErrorNotFound("Unknown newsletter issue"));
+    };
+
+    let counts = get_delivery_counts(&pool, newsletter_issue_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let failures = get_failed_deliveries(&pool, newsletter_issue_id, &pagination)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "newsletter_issue_id": newsletter_issue_id,
+        "title": issue.title,
+        "status": issue.status,
+        "scheduled_for": issue.scheduled_for.map(format_timestamp),
+        "counts": counts,
+        "failures": {
+            "page": pagination.page,
+            "page_size": pagination.page_size,
+            "total": counts.failed + counts.skipped,
+            "items": failures,
+        },
+    })))
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_delivery_counts(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<DeliveryCounts, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT status, COUNT(*) AS "count!"
+        FROM issue_deliveries
+        WHERE newsletter_issue_id = $1
+        GROUP BY status
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut counts = DeliveryCounts::default();
+    for row in rows {
+        let count = match row.status.as_str() {
+            "queued" => &mut counts.queued,
+            "sent" => &mut counts.sent,
+            "failed" => &mut counts.failed,
+            "skipped" => &mut counts.skipped,
+            status => {
+                tracing::warn!(status, "Unknown delivery status");
+                continue;
+            }
+        };
+        *count = row.count;
+    }
+    Ok(counts)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_failed_deliveries(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    pagination: &Pagination,
+) -> Result<Vec<FailedDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        FailedDelivery,
+        r#"
+        SELECT subscriber_email, status, error
+        FROM issue_deliveries
+        WHERE newsletter_issue_id = $1 AND status IN ('failed', 'skipped')
+        ORDER BY subscriber_email
+        LIMIT $2 OFFSET $3
+        "#,
+        newsletter_issue_id,
+        pagination.page_size,
+        (pagination.page - 1) * pagination.page_size,
+    )
+    .fetch_all(pool)
+    .await
+}
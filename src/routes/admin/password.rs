@@ -0,0 +1,81 @@
+use actix_web::{
+    error::ErrorInternalServerError,
+    http::header::{ContentType, LOCATION},
+    web, HttpResponse,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::authentication::{
+    change_password as store_password, get_username, validate_credentials, AuthError, Credentials,
+    UserId,
+};
+use crate::domain::NewPassword;
+use crate::{FlashMessage, IncomingFlashMessages};
+
+pub async fn change_password_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("password.html"),
+            flash_html = flash_messages.to_html()
+        ))
+}
+
+#[derive(Deserialize)]
+pub struct FormData {
+    current_password: String,
+    new_password: String,
+    new_password_check: String,
+}
+
+#[tracing::instrument(skip(form, pool), fields(user_id = %*user_id))]
+pub async fn change_password(
+    form: web::Form<FormData>,
+    user_id: web::ReqData<UserId>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner().0;
+    let form = form.into_inner();
+
+    if form.new_password != form.new_password_check {
+        return Ok(redirect_to_form(FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )));
+    }
+    let new_password = match NewPassword::parse(form.new_password) {
+        Ok(new_password) => new_password,
+        Err(e) => return Ok(redirect_to_form(FlashMessage::error(e))),
+    };
+
+    let username = get_username(user_id, &pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let credentials = Credentials {
+        username,
+        password: form.current_password,
+    };
+    if let Err(e) = validate_credentials(credentials, &pool).await {
+        return match e {
+            AuthError::InvalidCredentials(_) => Ok(redirect_to_form(FlashMessage::error(
+                "The current password is incorrect.",
+            ))),
+            AuthError::UnexpectedError(e) => Err(ErrorInternalServerError(e.to_string())),
+        };
+    }
+
+    store_password(user_id, new_password.as_ref().to_string(), &pool)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    Ok(redirect_to_form(FlashMessage::success(
+        "Your password has been changed.",
+    )))
+}
+
+fn redirect_to_form(message: FlashMessage) -> HttpResponse {
+    let mut response = HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/password"))
+        .finish();
+    message.send(&mut response);
+    response
+}
@@ -1,7 +1,12 @@
 use actix_web::{http::header::ContentType, HttpResponse};
 
-pub async fn home() -> HttpResponse {
+use crate::IncomingFlashMessages;
+
+pub async fn home(flash_messages: IncomingFlashMessages) -> HttpResponse {
     HttpResponse::Ok()
         .content_type(ContentType::html())
-        .body(include_str!("home.html"))
+        .body(format!(
+            include_str!("home.html"),
+            flash_html = flash_messages.to_html()
+        ))
 }
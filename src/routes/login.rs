@@ -1,16 +1,91 @@
 use actix_web::{
-    http::header::{ContentType, LOCATION},
-    HttpResponse, Responder,
+    http::{header::ContentType, header::LOCATION, StatusCode},
+    web, HttpResponse, Responder, ResponseError,
 };
+use serde::Deserialize;
+use sqlx::PgPool;
 
-pub async fn login_page() -> impl Responder {
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::{FlashMessage, IncomingFlashMessages, TypedSession};
+
+pub async fn login_page(flash_messages: IncomingFlashMessages) -> impl Responder {
     HttpResponse::Ok()
         .content_type(ContentType::html())
-        .body(include_str!("login.html"))
+        .body(format!(
+            include_str!("login.html"),
+            flash_html = flash_messages.to_html()
+        ))
+}
+
+#[derive(Deserialize)]
+pub struct FormData {
+    username: String,
+    password: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum LoginError {
+    #[error("Authentication failed")]
+    AuthError(#[source] Box<dyn std::error::Error>),
+    #[error("Something went wrong")]
+    UnexpectedError(#[from] Box<dyn std::error::Error>),
+}
+
+impl std::fmt::Debug for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{e}\n")?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{cause}")?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SEE_OTHER
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::SeeOther()
+            .insert_header((LOCATION, "/login"))
+            .finish();
+        FlashMessage::error(self.to_string()).send(&mut response);
+        response
+    }
 }
 
-pub async fn login() -> impl Responder {
-    HttpResponse::SeeOther()
-        .insert_header((LOCATION, "/")) // redict to home page
+#[tracing::instrument(skip(form, pool, session), fields(username = %form.username))]
+pub async fn login(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<impl Responder, LoginError> {
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password,
+    };
+    let user_id = validate_credentials(credentials, &pool)
         .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials(_) => LoginError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => LoginError::UnexpectedError(e.into()),
+        })?;
+    // Rotate the session key on privilege change to prevent session fixation.
+    session.renew();
+    session
+        .insert_user_id(user_id)
+        .map_err(|e| LoginError::UnexpectedError(e.into()))?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/dashboard"))
+        .finish())
 }
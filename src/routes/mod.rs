@@ -1,18 +1,22 @@
+mod admin;
 mod home;
 mod login;
 mod newsletters;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
 
-use super::domain::SubscriberEmail;
-use super::EmailClient;
 use actix_web::{HttpResponse, Responder};
 
+pub use admin::*;
 pub use home::*;
 pub use login::*;
 pub use newsletters::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_unsubscribe::*;
 
 #[tracing::instrument]
 pub async fn health_check() -> impl Responder {
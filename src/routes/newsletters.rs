@@ -1,11 +1,28 @@
-use super::{EmailClient, SubscriberEmail};
-use actix_web::{http::StatusCode, web, HttpResponse, Responder, ResponseError};
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::newsletter_issue::create_issue;
+use crate::NewsletterTopics;
+use actix_web::{
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse, ResponseError,
+};
+use base64::Engine;
 use sqlx::PgPool;
+use time::OffsetDateTime;
 
 #[derive(serde::Deserialize, Debug)]
 pub struct BodyData {
     title: String,
     content: Content,
+    /// Only sent to the subscribers following this topic. Sent to everyone when missing.
+    #[serde(default)]
+    topic: Option<String>,
+    /// When to send the issue, as an RFC 3339 timestamp. Sent right away when missing.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    scheduled_for: Option<OffsetDateTime>,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -16,6 +33,12 @@ pub struct Content {
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
+    #[error("{0}")]
+    InvalidIdempotencyKey(String),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Authentication failed")]
+    AuthError(#[source] Box<dyn std::error::Error>),
     #[error(transparent)]
     UnexpectedError(#[from] Box<dyn std::error::Error>),
 }
@@ -42,75 +65,116 @@ fn error_chain_fmt(
 impl ResponseError for PublishError {
     fn status_code(&self) -> StatusCode {
         match self {
+            PublishError::InvalidIdempotencyKey(_) | PublishError::ValidationError(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            PublishError::AuthError(_) => StatusCode::UNAUTHORIZED,
             PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status_code());
+        if let PublishError::AuthError(_) = self {
+            let header_value = HeaderValue::from_static(r#"Basic realm="publish""#);
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header_value);
+        }
+        response
+    }
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(request, pool, topics), fields(username = tracing::field::Empty))]
 pub async fn post_newsletter(
     body: web::Json<BodyData>,
+    request: HttpRequest,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-) -> Result<impl Responder, PublishError> {
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .map_err(|e| PublishError::UnexpectedError(e.into()))?;
-            }
-            Err(error) => {
-                tracing::warn!(
-                error.cause_chain = ?error,
-                "Skipping a confirmed subscriber. \
-                Their stored contact details are invalid",
-                );
-            }
-        }
-    }
+    topics: web::Data<NewsletterTopics>,
+) -> Result<HttpResponse, PublishError> {
+    let credentials = basic_authentication(request.headers()).map_err(PublishError::AuthError)?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let user_id = validate_credentials(credentials, &pool)
+        .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
+        })?;
 
-    Ok(HttpResponse::Ok().await)
-}
+    let topic = topics
+        .parse_issue_topic(body.topic.as_deref())
+        .map_err(PublishError::ValidationError)?;
+    let idempotency_key = idempotency_key(request.headers())?;
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, idempotency_key, user_id).await? {
+            NextAction::StartProcessing(transaction) => transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .map_err(|e| PublishError::UnexpectedError(e.into()))?,
+    };
 
-#[derive(Debug)]
-pub struct ConfirmedSubscriber {
-    email: SubscriberEmail,
-}
+    let (issue_id, status) = create_issue(
+        &mut transaction,
+        &body.title,
+        &body.content.text,
+        &body.content.html,
+        topic.as_deref(),
+        body.scheduled_for,
+    )
+    .await
+    .map_err(|e| PublishError::UnexpectedError(e.into()))?;
 
-impl std::fmt::Display for ConfirmedSubscriber {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.email.fmt(f)
+    let response = HttpResponse::Accepted().json(serde_json::json!({
+        "newsletter_issue_id": issue_id,
+        "status": status.as_str(),
+    }));
+    match idempotency_key {
+        Some(idempotency_key) => {
+            Ok(save_response(transaction, &idempotency_key, user_id, response).await?)
+        }
+        None => {
+            transaction
+                .commit()
+                .await
+                .map_err(|e| PublishError::UnexpectedError(e.into()))?;
+            Ok(response)
+        }
     }
 }
 
-type ConfirmedSubscriberResult = Result<ConfirmedSubscriber, Box<dyn std::error::Error>>;
+/// Reads the optional `Idempotency-Key` header: retries carrying the same key get the
+/// response of the first request instead of publishing the issue again.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<IdempotencyKey>, PublishError> {
+    headers
+        .get("Idempotency-Key")
+        .map(|value| {
+            let value = value
+                .to_str()
+                .map_err(|e| PublishError::InvalidIdempotencyKey(e.to_string()))?;
+            IdempotencyKey::try_from(value.to_string()).map_err(PublishError::InvalidIdempotencyKey)
+        })
+        .transpose()
+}
+
+fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, Box<dyn std::error::Error>> {
+    let header_value = headers
+        .get(header::AUTHORIZATION)
+        .ok_or("The 'Authorization' header was missing")?
+        .to_str()?;
+    let base64_encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD.decode(base64_encoded_segment)?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)?;
 
-#[tracing::instrument(skip(pool))]
-async fn get_confirmed_subscribers(
-    pool: &PgPool,
-) -> Result<Vec<ConfirmedSubscriberResult>, Box<dyn std::error::Error>> {
-    let confirmed_subscribers = sqlx::query!(
-        r#"
-        SELECT email
-        FROM subscriptions
-        WHERE status = 'confirmed'
-        "#,
-    )
-    .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|r| match SubscriberEmail::parse(r.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
-        Err(error) => Err(error.into()),
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .ok_or("A password must be provided in 'Basic' auth.")?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
     })
-    .collect();
-    Ok(confirmed_subscribers)
 }
@@ -1,13 +1,14 @@
 use actix_web::{http::StatusCode, web, HttpResponse, Responder, ResponseError};
 use rand::Rng;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use sqlx::{PgPool, Postgres, Transaction};
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
     domain::{Subscriber, SubscriberName},
-    EmailClient, SubscriberEmail,
+    EmailError, EmailSender, SubscriberEmail,
 };
 
 #[derive(Deserialize, Debug)]
@@ -29,6 +30,9 @@ impl TryFrom<FormData> for Subscriber {
 #[derive(Debug)]
 pub struct ApplicationBaseUrl(pub String);
 
+/// How long a confirmation link stays valid.
+pub const SUBSCRIPTION_TOKEN_TTL: Duration = Duration::hours(24);
+
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
     #[error("{0}")]
@@ -65,11 +69,14 @@ impl ResponseError for SubscribeError {
     }
 }
 
+/// Subscribes the given address. Submitting an address that is already known does not fail:
+/// it gets a new confirmation email or, once confirmed, a notice that it is already subscribed.
+/// The response is the same in every case, so that it does not tell whether the address existed.
 #[tracing::instrument(skip(db_pool))]
 pub async fn subscribe(
     form: web::Form<FormData>,
     db_pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    email_client: web::Data<dyn EmailSender>,
     app_base_url: web::Data<ApplicationBaseUrl>,
 ) -> Result<impl Responder, SubscribeError> {
     let subscriber = Subscriber::try_from(form.0).map_err(SubscribeError::ValidationError)?;
@@ -77,11 +84,42 @@ pub async fn subscribe(
         .begin()
         .await
         .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
-    let subscriber_id = insert_subscriber(&mut transaction, &subscriber)
+    // Inserting first, rather than looking the address up, keeps concurrent requests for a new
+    // address from both inserting it: the second one waits for the first and takes the row.
+    let inserted = insert_subscriber(&mut transaction, &subscriber)
         .await
         .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
+    let subscriber_id = match inserted {
+        Some(subscriber_id) => subscriber_id,
+        None => {
+            let (subscriber_id, status) =
+                get_existing_subscription(&mut transaction, &subscriber.email)
+                    .await
+                    .map_err(|e| SubscribeError::UnexpectedError(e.into()))?
+                    .ok_or_else(|| {
+                        SubscribeError::UnexpectedError(
+                            "The conflicting subscription could not be found.".into(),
+                        )
+                    })?;
+            if status == "confirmed" {
+                transaction
+                    .commit()
+                    .await
+                    .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
+                send_already_subscribed_email(email_client.get_ref(), &subscriber)
+                    .await
+                    .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
+                return Ok(HttpResponse::Ok());
+            }
+            // Pending and unsubscribed addresses go through double opt-in again, with a new token.
+            restart_confirmation(&mut transaction, subscriber_id)
+                .await
+                .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
+            subscriber_id
+        }
+    };
     let subscription_token = generate_subscription_token().await;
-    store_token(&mut transaction, &subscriber_id, &subscription_token)
+    store_token(&mut transaction, &subscriber_id, &subscription_token, None)
         .await
         .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
     transaction
@@ -89,7 +127,7 @@ pub async fn subscribe(
         .await
         .map_err(|e| SubscribeError::UnexpectedError(e.into()))?;
     send_confirmation_email(
-        &email_client,
+        email_client.get_ref(),
         &subscriber,
         &app_base_url,
         &subscription_token,
@@ -99,7 +137,7 @@ pub async fn subscribe(
     Ok(HttpResponse::Ok())
 }
 
-async fn generate_subscription_token() -> String {
+pub async fn generate_subscription_token() -> String {
     let mut rng = rand::thread_rng();
     std::iter::repeat_with(|| rng.sample(rand::distributions::Alphanumeric))
         .map(char::from)
@@ -107,38 +145,94 @@ async fn generate_subscription_token() -> String {
         .collect()
 }
 
+/// What is stored in place of a subscription token, so that reading the database is not enough
+/// to confirm subscriptions. Tokens are long and random, which makes a plain SHA-256 enough.
+pub fn hash_subscription_token(subscription_token: &str) -> String {
+    Sha256::digest(subscription_token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Inserts a pending subscriber, unless the address is already known. Returns the id of the new
+/// subscriber, or nothing when the address was known.
 #[tracing::instrument]
 async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber: &Subscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let id = Uuid::new_v4();
-    sqlx::query!(
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
         r#"
             INSERT INTO subscriptions (id, email, name, subscribed_at, status)
             VALUES ($1, $2, $3, $4, 'pending_confirmation')
+            ON CONFLICT (email) DO NOTHING
+            RETURNING id
             "#,
-        id,
+        Uuid::new_v4(),
         subscriber.email.as_ref(),
         subscriber.name.as_ref(),
         OffsetDateTime::now_utc(),
     )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|r| r.id))
+}
+
+#[tracing::instrument]
+async fn get_existing_subscription(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, status FROM subscriptions WHERE email = $1 FOR UPDATE"#,
+        email.as_ref(),
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|r| (r.id, r.status)))
+}
+
+/// Puts a subscriber back to pending confirmation, invalidating the confirmation links sent
+/// so far.
+#[tracing::instrument]
+async fn restart_confirmation(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'pending_confirmation', confirmed_at = NULL WHERE id = $1"#,
+        subscriber_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND new_email IS NULL"#,
+        subscriber_id,
+    )
     .execute(transaction)
     .await?;
-    Ok(id)
+    Ok(())
 }
 
+/// Stores a token confirming the subscription of `subscriber_id` or, with `new_email`, their
+/// move to that address. It expires after [`SUBSCRIPTION_TOKEN_TTL`].
 #[tracing::instrument]
-async fn store_token(
+pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: &Uuid,
     subscription_token: &str,
+    new_email: Option<&str>,
 ) -> Result<(), sqlx::Error> {
+    let created_at = OffsetDateTime::now_utc();
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-VALUES ($1, $2)"#,
-        subscription_token,
-        subscriber_id
+        r#"INSERT INTO subscription_tokens
+(subscription_token_hash, subscriber_id, new_email, created_at, expires_at)
+VALUES ($1, $2, $3, $4, $5)"#,
+        hash_subscription_token(subscription_token),
+        subscriber_id,
+        new_email,
+        created_at,
+        created_at + SUBSCRIPTION_TOKEN_TTL,
     )
     .execute(transaction)
     .await?;
@@ -146,12 +240,12 @@ VALUES ($1, $2)"#,
 }
 
 #[tracing::instrument]
-async fn send_confirmation_email(
-    email_client: &EmailClient,
+pub async fn send_confirmation_email(
+    email_client: &dyn EmailSender,
     subscriber: &Subscriber,
     app_base_url: &ApplicationBaseUrl,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), EmailError> {
     let app_base_url = &app_base_url.0;
     let confirmation_link =
         format!("{app_base_url}/subscriptions/confirm?subscription_token={subscription_token}");
@@ -170,3 +264,21 @@ async fn send_confirmation_email(
         .await?;
     Ok(())
 }
+
+#[tracing::instrument]
+async fn send_already_subscribed_email(
+    email_client: &dyn EmailSender,
+    subscriber: &Subscriber,
+) -> Result<(), EmailError> {
+    email_client
+        .send_email(
+            &subscriber.email,
+            "You are already subscribed",
+            "Someone, hopefully you, asked to subscribe this address to our newsletter.<br />\
+            It is already subscribed: there is nothing more to do.",
+            "Someone, hopefully you, asked to subscribe this address to our newsletter.\n\
+            It is already subscribed: there is nothing more to do.",
+        )
+        .await?;
+    Ok(())
+}
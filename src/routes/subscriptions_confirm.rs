@@ -1,8 +1,20 @@
-use actix_web::{http::StatusCode, web, HttpResponse, Responder, ResponseError};
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse, ResponseError,
+};
+use askama::Template;
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
+use super::{
+    generate_subscription_token, hash_subscription_token, send_confirmation_email,
+    send_email_change_confirmation, store_token, ApplicationBaseUrl,
+};
+use crate::domain::{Subscriber, SubscriberName};
+use crate::{EmailSender, SubscriberEmail};
+
 #[derive(Deserialize, Debug)]
 pub struct ConfirmationQuery {
     subscription_token: String,
@@ -12,6 +24,12 @@ pub struct ConfirmationQuery {
 pub enum ConfirmSubscriptionError {
     #[error("{0}")]
     Unauthorized(String),
+    #[error("The confirmation link has expired.")]
+    TokenExpired { subscription_token: String },
+    #[error("The confirmation link has already been used.")]
+    TokenAlreadyUsed,
+    #[error("{0}")]
+    Conflict(String),
     #[error(transparent)]
     UnexpectedError(#[from] Box<dyn std::error::Error>),
 }
@@ -39,49 +57,371 @@ impl ResponseError for ConfirmSubscriptionError {
     fn status_code(&self) -> StatusCode {
         match self {
             ConfirmSubscriptionError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ConfirmSubscriptionError::TokenExpired { .. } => StatusCode::GONE,
+            ConfirmSubscriptionError::TokenAlreadyUsed => StatusCode::CONFLICT,
+            ConfirmSubscriptionError::Conflict(_) => StatusCode::CONFLICT,
             ConfirmSubscriptionError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let page = match self {
+            ConfirmSubscriptionError::Unauthorized(_) => InvalidTokenPage.render(),
+            ConfirmSubscriptionError::TokenExpired { subscription_token } => {
+                ExpiredTokenPage { subscription_token }.render()
+            }
+            ConfirmSubscriptionError::TokenAlreadyUsed => UsedTokenPage.render(),
+            ConfirmSubscriptionError::Conflict(message) => ErrorPage { message }.render(),
+            ConfirmSubscriptionError::UnexpectedError(_) => {
+                return HttpResponse::new(self.status_code())
+            }
+        };
+        match page {
+            Ok(body) => HttpResponse::build(self.status_code())
+                .content_type(ContentType::html())
+                .body(body),
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, "Failed to render a confirmation page");
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/confirmed.html")]
+struct ConfirmedPage {
+    email_changed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/already_confirmed.html")]
+struct AlreadyConfirmedPage;
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/used_token.html")]
+struct UsedTokenPage;
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/invalid_token.html")]
+struct InvalidTokenPage;
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/expired_token.html")]
+struct ExpiredTokenPage<'a> {
+    subscription_token: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/link_resent.html")]
+struct LinkResentPage;
+
+#[derive(Template)]
+#[template(path = "subscriptions_confirm/error.html")]
+struct ErrorPage<'a> {
+    message: &'a str,
+}
+
+fn html_page(page: impl Template) -> Result<HttpResponse, ConfirmSubscriptionError> {
+    let body = page
+        .render()
+        .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(body))
+}
+
+/// A subscription token confirms either a new subscription or, when it carries `new_email`, a
+/// change of address requested from the preference center.
+#[derive(Debug)]
+pub struct SubscriptionToken {
+    pub subscriber_id: Uuid,
+    pub new_email: Option<String>,
+    pub expires_at: OffsetDateTime,
+    pub used_at: Option<OffsetDateTime>,
+}
+
+impl SubscriptionToken {
+    fn is_expired(&self) -> bool {
+        self.expires_at <= OffsetDateTime::now_utc()
+    }
+}
+
+/// What following a confirmation link did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Confirmation {
+    Confirmed,
+    EmailChanged,
+    /// The link was followed before, or the subscriber confirmed through another link.
+    AlreadyConfirmed,
 }
 
-#[tracing::instrument]
+/// Confirms a subscription, or a change of address, in a single transaction that also consumes
+/// the token. Following the link again is harmless and says so.
+#[tracing::instrument(skip(query, db_pool))]
 pub async fn confirm_subscription(
     query: web::Query<ConfirmationQuery>,
     db_pool: web::Data<PgPool>,
-) -> Result<impl Responder, ConfirmSubscriptionError> {
-    let subscriber_id = get_subscriber_id_from_token(&db_pool, &query.subscription_token)
+) -> Result<HttpResponse, ConfirmSubscriptionError> {
+    let mut transaction = db_pool
+        .begin()
         .await
         .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
-    subscriber_id.ok_or_else(|| {
-        ConfirmSubscriptionError::Unauthorized("Invalid subscription token".into())
-    })?;
-    confirm_subscriber(&db_pool, subscriber_id.unwrap())
+    let token = get_subscription_token(&mut transaction, &query.subscription_token)
+        .await
+        .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?
+        .ok_or_else(|| {
+            ConfirmSubscriptionError::Unauthorized("Invalid subscription token".into())
+        })?;
+    if token.used_at.is_none() && token.is_expired() {
+        return Err(ConfirmSubscriptionError::TokenExpired {
+            subscription_token: query.0.subscription_token,
+        });
+    }
+    let confirmation = match (&token.new_email, token.used_at) {
+        (Some(_), Some(_)) => return Err(ConfirmSubscriptionError::TokenAlreadyUsed),
+        (Some(new_email), None) => {
+            let changed = change_subscriber_email(&mut transaction, token.subscriber_id, new_email)
+                .await
+                .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+            if !changed {
+                return Err(ConfirmSubscriptionError::Conflict(format!(
+                    "{new_email} is already subscribed"
+                )));
+            }
+            Confirmation::EmailChanged
+        }
+        (None, used_at) => {
+            let confirmed = used_at.is_none()
+                && confirm_subscriber(&mut transaction, token.subscriber_id)
+                    .await
+                    .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+            if confirmed {
+                Confirmation::Confirmed
+            } else {
+                let status = get_subscription_status(&mut transaction, token.subscriber_id)
+                    .await
+                    .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+                match (status.as_str(), used_at) {
+                    ("confirmed", _) => Confirmation::AlreadyConfirmed,
+                    (_, Some(_)) => return Err(ConfirmSubscriptionError::TokenAlreadyUsed),
+                    (_, None) => {
+                        return Err(ConfirmSubscriptionError::Unauthorized(format!(
+                            "The subscription is {status}, not pending confirmation"
+                        )))
+                    }
+                }
+            }
+        }
+    };
+    if confirmation != Confirmation::AlreadyConfirmed {
+        mark_token_as_used(&mut transaction, &query.subscription_token)
+            .await
+            .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    }
+    transaction
+        .commit()
         .await
         .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
-    Ok(HttpResponse::Ok())
+
+    match confirmation {
+        Confirmation::Confirmed => html_page(ConfirmedPage {
+            email_changed: false,
+        }),
+        Confirmation::EmailChanged => html_page(ConfirmedPage {
+            email_changed: true,
+        }),
+        Confirmation::AlreadyConfirmed => html_page(AlreadyConfirmedPage),
+    }
 }
 
-#[tracing::instrument]
-pub async fn confirm_subscriber(db_pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+/// Sends a new confirmation link in place of an expired one, which stops being valid.
+#[tracing::instrument(skip(query, db_pool, email_client, app_base_url))]
+pub async fn resend_confirmation(
+    query: web::Query<ConfirmationQuery>,
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    app_base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, ConfirmSubscriptionError> {
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    let token = get_subscription_token(&mut transaction, &query.subscription_token)
+        .await
+        .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?
+        .ok_or_else(|| {
+            ConfirmSubscriptionError::Unauthorized("Invalid subscription token".into())
+        })?;
+    if token.used_at.is_some() {
+        return Err(ConfirmSubscriptionError::TokenAlreadyUsed);
+    }
+    if !token.is_expired() {
+        return Err(ConfirmSubscriptionError::Conflict(
+            "The confirmation link has not expired yet.".into(),
+        ));
+    }
+    let subscriber = get_subscriber(&mut transaction, token.subscriber_id)
+        .await
+        .map_err(ConfirmSubscriptionError::UnexpectedError)?;
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+        r#"DELETE FROM subscription_tokens WHERE subscription_token_hash = $1"#,
+        hash_subscription_token(&query.subscription_token),
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    let subscription_token = generate_subscription_token().await;
+    store_token(
+        &mut transaction,
+        &token.subscriber_id,
+        &subscription_token,
+        token.new_email.as_deref(),
+    )
+    .await
+    .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+
+    match token.new_email {
+        Some(new_email) => {
+            let new_email = SubscriberEmail::parse(new_email)
+                .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+            send_email_change_confirmation(
+                email_client.get_ref(),
+                &new_email,
+                &app_base_url,
+                &subscription_token,
+            )
+            .await
+        }
+        None => {
+            send_confirmation_email(
+                email_client.get_ref(),
+                &subscriber,
+                &app_base_url,
+                &subscription_token,
+            )
+            .await
+        }
+    }
+    .map_err(|e| ConfirmSubscriptionError::UnexpectedError(e.into()))?;
+    html_page(LinkResentPage)
+}
+
+/// Moves a subscriber from pending confirmation to confirmed. Returns `false`, leaving the
+/// subscriber untouched, when they were not pending confirmation.
+#[tracing::instrument(skip(transaction))]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'confirmed', confirmed_at = now()
+        WHERE id = $1 AND status = 'pending_confirmation'
+        "#,
         subscriber_id,
     )
-    .execute(db_pool)
+    .execute(transaction)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn get_subscription_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1"#,
+        subscriber_id,
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(row.status)
+}
+
+/// Moves a subscriber to `new_email`, unless another subscription uses it by now. Returns
+/// whether the address was changed.
+#[tracing::instrument(skip(transaction))]
+pub async fn change_subscriber_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET email = $2
+        WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE email = $2 AND id <> $1)
+        "#,
+        subscriber_id,
+        new_email,
+    )
+    .execute(transaction)
+    .await;
+    match result {
+        Ok(result) => Ok(result.rows_affected() > 0),
+        // Another subscription took the address concurrently.
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(UNIQUE_VIOLATION) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The Postgres error code of a unique constraint violation.
+const UNIQUE_VIOLATION: &str = "23505";
+
+#[tracing::instrument(skip(transaction))]
+async fn mark_token_as_used(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscription_tokens SET used_at = now() WHERE subscription_token_hash = $1"#,
+        hash_subscription_token(subscription_token),
+    )
+    .execute(transaction)
     .await?;
     Ok(())
 }
 
-#[tracing::instrument]
-pub async fn get_subscriber_id_from_token(
-    db_pool: &PgPool,
+#[tracing::instrument(skip(transaction))]
+async fn get_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Subscriber, Box<dyn std::error::Error>> {
+    let row = sqlx::query!(
+        r#"SELECT name, email FROM subscriptions WHERE id = $1"#,
+        subscriber_id,
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(Subscriber {
+        name: SubscriberName::parse(row.name)?,
+        email: SubscriberEmail::parse(row.email)?,
+    })
+}
+
+/// Looks a token up and locks it until the end of the transaction, so that it is consumed only
+/// once.
+#[tracing::instrument(skip(transaction))]
+pub async fn get_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
-        subscription_token,
+) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        SubscriptionToken,
+        r#"
+        SELECT subscriber_id, new_email, expires_at, used_at
+        FROM subscription_tokens
+        WHERE subscription_token_hash = $1
+        FOR UPDATE
+        "#,
+        hash_subscription_token(subscription_token),
     )
-    .fetch_optional(db_pool)
+    .fetch_optional(transaction)
     .await?;
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(result)
 }
@@ -0,0 +1,353 @@
+use actix_web::{
+    http::header::{ContentType, LOCATION},
+    http::StatusCode,
+    web, HttpResponse, ResponseError,
+};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{generate_subscription_token, store_token, ApplicationBaseUrl};
+use crate::domain::SubscriberName;
+use crate::{
+    EmailError, EmailSender, FlashMessage, IncomingFlashMessages, LinkPurpose, SubscriberEmail,
+    SubscriberLinks,
+};
+
+/// The topics subscribers can pick from in the preference center.
+#[derive(Debug)]
+pub struct NewsletterTopics(pub Vec<String>);
+
+impl NewsletterTopics {
+    /// Checks the topic of an issue: a blank one means the issue is for every subscriber.
+    pub fn parse_issue_topic(&self, topic: Option<&str>) -> Result<Option<String>, String> {
+        match topic.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(topic) if self.0.iter().any(|known| known == topic) => Ok(Some(topic.to_string())),
+            Some(topic) => Err(format!("Unknown topic: {topic}")),
+        }
+    }
+}
+
+#[derive(sqlx::Type, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum DigestFrequency {
+    Immediate,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    const ALL: [DigestFrequency; 3] = [
+        DigestFrequency::Immediate,
+        DigestFrequency::Daily,
+        DigestFrequency::Weekly,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestFrequency::Immediate => "immediate",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|frequency| frequency.as_str() == s)
+            .ok_or_else(|| format!("Invalid digest frequency: {s}"))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreferencesQuery {
+    token: String,
+}
+
+#[derive(Debug)]
+struct Preferences {
+    name: String,
+    email: String,
+    topics: Vec<String>,
+    digest_frequency: DigestFrequency,
+}
+
+#[derive(Debug)]
+struct NewPreferences {
+    name: SubscriberName,
+    email: SubscriberEmail,
+    topics: Vec<String>,
+    digest_frequency: DigestFrequency,
+}
+
+impl NewPreferences {
+    /// Parses the submitted form. It comes as raw pairs because every picked topic repeats the
+    /// `topics` field.
+    fn parse(form: Vec<(String, String)>, known_topics: &[String]) -> Result<Self, String> {
+        let (mut name, mut email, mut digest_frequency) = (None, None, None);
+        let mut topics = Vec::new();
+        for (field, value) in form {
+            match field.as_str() {
+                "name" => name = Some(value),
+                "email" => email = Some(value),
+                "digest_frequency" => digest_frequency = Some(value),
+                "topics" => {
+                    if !known_topics.contains(&value) {
+                        return Err(format!("Unknown topic: {value}"));
+                    }
+                    if !topics.contains(&value) {
+                        topics.push(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            name: SubscriberName::parse(name.unwrap_or_default())?,
+            email: SubscriberEmail::parse(email.unwrap_or_default())?,
+            topics,
+            digest_frequency: DigestFrequency::parse(&digest_frequency.unwrap_or_default())?,
+        })
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] Box<dyn std::error::Error>),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{e}\n")?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{cause}")?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(skip(db_pool, subscriber_links, topics, flash_messages))]
+pub async fn preferences_page(
+    query: web::Query<PreferencesQuery>,
+    db_pool: web::Data<PgPool>,
+    subscriber_links: web::Data<SubscriberLinks>,
+    topics: web::Data<NewsletterTopics>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = verify_token(&subscriber_links, &query.token)?;
+    let preferences = get_preferences(&db_pool, subscriber_id).await?;
+
+    let topics_html: String = topics
+        .0
+        .iter()
+        .map(|topic| {
+            let checked = if preferences.topics.contains(topic) {
+                " checked"
+            } else {
+                ""
+            };
+            let topic = htmlescape::encode_minimal(topic);
+            format!(
+                "<label><input type=\"checkbox\" name=\"topics\" value=\"{topic}\"{checked}> {topic}</label><br>"
+            )
+        })
+        .collect();
+    let digest_frequency_html: String = DigestFrequency::ALL
+        .iter()
+        .map(|frequency| {
+            let selected = if *frequency == preferences.digest_frequency {
+                " selected"
+            } else {
+                ""
+            };
+            let frequency = frequency.as_str();
+            format!("<option value=\"{frequency}\"{selected}>{frequency}</option>")
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("preferences.html"),
+            flash_html = flash_messages.to_html(),
+            token = htmlescape::encode_minimal(&query.token),
+            name = htmlescape::encode_minimal(&preferences.name),
+            email = htmlescape::encode_minimal(&preferences.email),
+            topics_html = topics_html,
+            digest_frequency_html = digest_frequency_html,
+            unsubscribe_link = htmlescape::encode_minimal(
+                &subscriber_links.link(LinkPurpose::Unsubscribe, subscriber_id)
+            ),
+        )))
+}
+
+/// Saves the submitted preferences. A new email address only replaces the current one once
+/// the subscriber follows the confirmation link sent to it.
+#[tracing::instrument(skip(form, db_pool, subscriber_links, topics, email_client, app_base_url))]
+pub async fn update_preferences(
+    query: web::Query<PreferencesQuery>,
+    form: web::Form<Vec<(String, String)>>,
+    db_pool: web::Data<PgPool>,
+    subscriber_links: web::Data<SubscriberLinks>,
+    topics: web::Data<NewsletterTopics>,
+    email_client: web::Data<dyn EmailSender>,
+    app_base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = verify_token(&subscriber_links, &query.token)?;
+    let current = get_preferences(&db_pool, subscriber_id).await?;
+    let preferences = match NewPreferences::parse(form.into_inner(), &topics.0) {
+        Ok(preferences) => preferences,
+        Err(e) => return Ok(redirect_to_page(&query.token, vec![FlashMessage::error(e)])),
+    };
+    let new_email = (preferences.email.as_ref() != current.email).then_some(&preferences.email);
+
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .map_err(|e| PreferencesError::UnexpectedError(e.into()))?;
+    let subscription_token = match new_email {
+        // Whether another subscription uses the address is only checked once the change is
+        // confirmed, so that the response never tells whether an address is subscribed.
+        Some(new_email) => {
+            let subscription_token = generate_subscription_token().await;
+            store_token(
+                &mut transaction,
+                &subscriber_id,
+                &subscription_token,
+                Some(new_email.as_ref()),
+            )
+            .await
+            .map_err(|e| PreferencesError::UnexpectedError(e.into()))?;
+            Some(subscription_token)
+        }
+        None => None,
+    };
+    store_preferences(&mut transaction, subscriber_id, &preferences)
+        .await
+        .map_err(|e| PreferencesError::UnexpectedError(e.into()))?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| PreferencesError::UnexpectedError(e.into()))?;
+
+    let mut messages = vec![FlashMessage::success("Your preferences have been updated.")];
+    if let (Some(new_email), Some(subscription_token)) = (new_email, subscription_token) {
+        send_email_change_confirmation(
+            email_client.get_ref(),
+            new_email,
+            &app_base_url,
+            &subscription_token,
+        )
+        .await
+        .map_err(|e| PreferencesError::UnexpectedError(e.into()))?;
+        messages.push(FlashMessage::info(format!(
+            "We have sent a confirmation link to {new_email}: your email address will change once you follow it."
+        )));
+    }
+    Ok(redirect_to_page(&query.token, messages))
+}
+
+fn redirect_to_page(token: &str, messages: Vec<FlashMessage>) -> HttpResponse {
+    let mut response = HttpResponse::SeeOther()
+        .insert_header((
+            LOCATION,
+            format!("/subscriptions/preferences?token={token}"),
+        ))
+        .finish();
+    for message in messages {
+        message.send(&mut response);
+    }
+    response
+}
+
+fn verify_token(subscriber_links: &SubscriberLinks, token: &str) -> Result<Uuid, PreferencesError> {
+    subscriber_links
+        .verify(LinkPurpose::Preferences, token)
+        .ok_or_else(|| PreferencesError::Unauthorized("Invalid preferences token".into()))
+}
+
+#[tracing::instrument(skip(db_pool))]
+async fn get_preferences(
+    db_pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Preferences, PreferencesError> {
+    sqlx::query_as!(
+        Preferences,
+        r#"
+        SELECT name, email, topics, digest_frequency as "digest_frequency: _"
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| PreferencesError::UnexpectedError(e.into()))?
+    .ok_or_else(|| PreferencesError::Unauthorized("The subscriber does not exist".into()))
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn store_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    preferences: &NewPreferences,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET name = $2, topics = $3, digest_frequency = $4
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        preferences.name.as_ref(),
+        &preferences.topics,
+        preferences.digest_frequency.as_str(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(email_client))]
+pub async fn send_email_change_confirmation(
+    email_client: &dyn EmailSender,
+    new_email: &SubscriberEmail,
+    app_base_url: &ApplicationBaseUrl,
+    subscription_token: &str,
+) -> Result<(), EmailError> {
+    let app_base_url = &app_base_url.0;
+    let confirmation_link =
+        format!("{app_base_url}/subscriptions/confirm?subscription_token={subscription_token}");
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new email address",
+            &format!(
+                "Click <a href=\"{confirmation_link}\">here</a> to receive our newsletter at this address."
+            ),
+            &format!("Visit {confirmation_link} to receive our newsletter at this address."),
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use actix_web::{http::header::ContentType, http::StatusCode, web, HttpResponse, ResponseError};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{LinkPurpose, SubscriberLinks};
+
+#[derive(Deserialize, Debug)]
+pub struct UnsubscribeQuery {
+    token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] Box<dyn std::error::Error>),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{e}\n")?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{cause}")?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Asks for confirmation before unsubscribing: `GET` must not change anything, since mail
+/// scanners follow the links they find.
+#[tracing::instrument(skip(subscriber_links))]
+pub async fn unsubscribe_page(
+    query: web::Query<UnsubscribeQuery>,
+    subscriber_links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    verify_token(&subscriber_links, &query.token)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            include_str!("unsubscribe.html"),
+            token = htmlescape::encode_minimal(&query.token)
+        )))
+}
+
+/// Unsubscribes the token's owner. This is also the RFC 8058 one-click endpoint that mail
+/// clients `POST` to, as advertised in the `List-Unsubscribe` header.
+#[tracing::instrument(skip(db_pool, subscriber_links))]
+pub async fn unsubscribe(
+    query: web::Query<UnsubscribeQuery>,
+    db_pool: web::Data<PgPool>,
+    subscriber_links: web::Data<SubscriberLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let subscriber_id = verify_token(&subscriber_links, &query.token)?;
+    unsubscribe_subscriber(&db_pool, subscriber_id)
+        .await
+        .map_err(|e| UnsubscribeError::UnexpectedError(e.into()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("unsubscribed.html")))
+}
+
+fn verify_token(subscriber_links: &SubscriberLinks, token: &str) -> Result<Uuid, UnsubscribeError> {
+    subscriber_links
+        .verify(LinkPurpose::Unsubscribe, token)
+        .ok_or_else(|| UnsubscribeError::Unauthorized("Invalid unsubscribe token".into()))
+}
+
+#[tracing::instrument(skip(db_pool))]
+pub async fn unsubscribe_subscriber(
+    db_pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1"#,
+        subscriber_id,
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+
+use actix_session::{
+    config::{CookieContentSecurity, PersistentSession, TtlExtensionPolicy},
+    storage::{generate_session_key, LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    Session, SessionExt, SessionGetError, SessionInsertError, SessionMiddleware,
+};
+use actix_web::{
+    cookie::{time::Duration, Key, SameSite},
+    dev::Payload,
+    FromRequest, HttpRequest,
+};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+type SessionState = HashMap<String, String>;
+
+/// Session storage backed by the `sessions` table.
+///
+/// Every session carries two deadlines: `expires_at` is pushed forward on activity (idle expiry),
+/// while `absolute_expires_at` is fixed when the session is created and caps `expires_at`.
+#[derive(Clone, Debug)]
+pub struct PgSessionStore {
+    db_pool: PgPool,
+    absolute_ttl: Duration,
+}
+
+impl PgSessionStore {
+    pub fn new(db_pool: PgPool, absolute_ttl: Duration) -> Self {
+        Self {
+            db_pool,
+            absolute_ttl,
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_expired(&self) -> Result<(), sqlx::Error> {
+        sqlx::query!(r#"DELETE FROM sessions WHERE expires_at <= now()"#)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl SessionStore for PgSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"SELECT state FROM sessions WHERE session_key = $1 AND expires_at > now()"#,
+            session_key.as_ref(),
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(|e| LoadError::Other(e.into()))?;
+        row.map(|r| serde_json::from_value(r.state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        self.delete_expired()
+            .await
+            .map_err(|e| SaveError::Other(e.into()))?;
+        let state =
+            serde_json::to_value(session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+        let session_key = generate_session_key();
+        let now = OffsetDateTime::now_utc();
+        let absolute_expires_at = now + self.absolute_ttl;
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (session_key, state, created_at, expires_at, absolute_expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            session_key.as_ref(),
+            state,
+            now,
+            std::cmp::min(now + *ttl, absolute_expires_at),
+            absolute_expires_at,
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| SaveError::Other(e.into()))?;
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_value(&session_state)
+            .map_err(|e| UpdateError::Serialization(e.into()))?;
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET state = $2, expires_at = LEAST($3, absolute_expires_at)
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref(),
+            state,
+            OffsetDateTime::now_utc() + *ttl,
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| UpdateError::Other(e.into()))?;
+        if result.rows_affected() == 0 {
+            // The session expired in the meantime: start over with a fresh key.
+            return self.save(session_state, ttl).await.map_err(|e| match e {
+                SaveError::Serialization(e) => UpdateError::Serialization(e),
+                SaveError::Other(e) => UpdateError::Other(e),
+            });
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET expires_at = LEAST($2, absolute_expires_at)
+            WHERE session_key = $1
+            "#,
+            session_key.as_ref(),
+            OffsetDateTime::now_utc() + *ttl,
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref(),
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Builds the session middleware: a signed, `HttpOnly`, `SameSite=Strict` cookie pointing at a
+/// row in the `sessions` table, whose idle timeout is refreshed on every request.
+pub fn session_middleware(
+    store: PgSessionStore,
+    key: Key,
+    idle_ttl: Duration,
+) -> SessionMiddleware<PgSessionStore> {
+    SessionMiddleware::builder(store, key)
+        .cookie_name("id".to_string())
+        .cookie_http_only(true)
+        .cookie_same_site(SameSite::Strict)
+        .cookie_content_security(CookieContentSecurity::Signed)
+        .session_lifecycle(
+            PersistentSession::default()
+                .session_ttl(idle_ttl)
+                .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+        )
+        .build()
+}
+
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    const USER_ID_KEY: &'static str = "user_id";
+
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::USER_ID_KEY, user_id)
+    }
+
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(Self::USER_ID_KEY)
+    }
+
+    pub fn log_out(self) {
+        self.0.purge()
+    }
+}
+
+impl FromRequest for TypedSession {
+    type Error = <Session as FromRequest>::Error;
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
@@ -6,9 +6,71 @@ pub struct Settings {
     pub app_port: u16,
     pub app_base_url: String,
     pub database_url: String,
+    /// Which provider sends emails; `email_base_url` is its API endpoint, or the relay URL for
+    /// SMTP.
+    #[serde(default)]
+    pub email_backend: EmailBackend,
     pub email_base_url: String,
+    #[serde(default)]
     pub email_auth_token: String,
+    /// Username to authenticate with on the SMTP relay, using `email_auth_token` as password.
+    pub email_smtp_username: Option<String>,
+    /// Where the outbox backend writes each email as an `.eml` file. Emails are only kept in
+    /// memory when missing.
+    pub email_outbox_directory: Option<String>,
+    /// A second provider that emails go to while the first one is failing.
+    pub email_fallback_backend: Option<EmailBackend>,
+    pub email_fallback_base_url: Option<String>,
+    pub email_fallback_auth_token: Option<String>,
+    pub email_fallback_smtp_username: Option<String>,
+    /// Consecutive failures after which a provider is skipped for `email_breaker_open_secs`.
+    pub email_breaker_failure_threshold: u32,
+    pub email_breaker_open_secs: u64,
+    /// Consecutive successful trial emails after which a skipped provider is used again.
+    pub email_breaker_success_threshold: u32,
+    /// Requests per second sent to each email provider with an HTTP API, with bursts of up to
+    /// `email_request_burst` requests. Must be positive.
+    pub email_requests_per_sec: f64,
+    pub email_request_burst: u32,
     pub email_sender: String,
+    pub hmac_secret: String,
+    /// Argon2id hash of the password of the `admin` user, created on startup when missing. Set
+    /// through the `ADMIN_PASSWORD_HASH` environment variable in production.
+    pub admin_password_hash: Option<String>,
+    pub session_idle_timeout_secs: i64,
+    pub session_absolute_timeout_secs: i64,
+    pub issue_delivery_workers: usize,
+    pub issue_delivery_max_attempts: u32,
+    pub issue_delivery_base_backoff_secs: u64,
+    pub issue_delivery_max_backoff_secs: u64,
+    /// Most deliveries of an issue sent together, in a single request to providers that
+    /// support batches.
+    pub issue_delivery_batch_size: u32,
+    pub issue_scheduler_interval_secs: u64,
+    pub token_cleanup_interval_secs: u64,
+    pub newsletter_topics: Vec<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailBackend {
+    #[default]
+    SendGrid,
+    Postmark,
+    Smtp,
+    /// Keeps emails instead of sending them, for local development and tests.
+    Outbox,
+}
+
+impl EmailBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailBackend::SendGrid => "sendgrid",
+            EmailBackend::Postmark => "postmark",
+            EmailBackend::Smtp => "smtp",
+            EmailBackend::Outbox => "outbox",
+        }
+    }
 }
 
 pub fn get_settings() -> Result<Settings, ConfigError> {
@@ -0,0 +1,148 @@
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a subscriber link lets its holder do. Each purpose signs its tokens differently, so a
+/// token cannot be reused for another purpose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPurpose {
+    Unsubscribe,
+    Preferences,
+}
+
+impl LinkPurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LinkPurpose::Unsubscribe => "unsubscribe",
+            LinkPurpose::Preferences => "preferences",
+        }
+    }
+}
+
+/// Builds and verifies the links put in every newsletter issue, which let subscribers
+/// unsubscribe or manage their preferences without an account.
+///
+/// The token is the subscriber id followed by its HMAC-SHA256 under the application secret, so
+/// links need no storage and cannot be forged for another subscriber.
+#[derive(Clone)]
+pub struct SubscriberLinks {
+    base_url: String,
+    key: Vec<u8>,
+}
+
+impl SubscriberLinks {
+    pub fn new(base_url: String, hmac_secret: &str) -> Self {
+        Self {
+            base_url,
+            key: hmac_secret.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn link(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> String {
+        format!(
+            "{}/subscriptions/{}?token={}",
+            self.base_url,
+            purpose.as_str(),
+            self.token(purpose, subscriber_id)
+        )
+    }
+
+    pub fn token(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> String {
+        let mut token = subscriber_id.as_bytes().to_vec();
+        token.extend(self.mac(purpose, subscriber_id).finalize().into_bytes());
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token)
+    }
+
+    /// Returns the subscriber id carried by `token`, if it was signed for `purpose`.
+    pub fn verify(&self, purpose: LinkPurpose, token: &str) -> Option<Uuid> {
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()?;
+        if token.len() <= 16 {
+            return None;
+        }
+        let (subscriber_id, signature) = token.split_at(16);
+        let subscriber_id = Uuid::from_slice(subscriber_id).ok()?;
+        self.mac(purpose, subscriber_id)
+            .verify_slice(signature)
+            .ok()?;
+        Some(subscriber_id)
+    }
+
+    fn mac(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(purpose.as_str().as_bytes());
+        mac.update(b":");
+        mac.update(subscriber_id.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkPurpose, SubscriberLinks};
+    use uuid::Uuid;
+
+    fn links() -> SubscriberLinks {
+        SubscriberLinks::new("http://127.0.0.1".to_string(), "secret")
+    }
+
+    #[test]
+    fn tokens_are_verified_back_to_their_subscriber() {
+        let subscriber_id = Uuid::new_v4();
+        let token = links().token(LinkPurpose::Unsubscribe, subscriber_id);
+        assert_eq!(
+            links().verify(LinkPurpose::Unsubscribe, &token),
+            Some(subscriber_id)
+        );
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_rejected() {
+        let other = SubscriberLinks::new("http://127.0.0.1".to_string(), "another secret");
+        let token = other.token(LinkPurpose::Unsubscribe, Uuid::new_v4());
+        assert_eq!(links().verify(LinkPurpose::Unsubscribe, &token), None);
+    }
+
+    #[test]
+    fn tokens_signed_for_another_purpose_are_rejected() {
+        let token = links().token(LinkPurpose::Unsubscribe, Uuid::new_v4());
+        assert_eq!(links().verify(LinkPurpose::Preferences, &token), None);
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let mut token = links()
+            .token(LinkPurpose::Unsubscribe, Uuid::new_v4())
+            .into_bytes();
+        token[0] = if token[0] == b'A' { b'B' } else { b'A' };
+        let token = String::from_utf8(token).unwrap();
+        assert_eq!(links().verify(LinkPurpose::Unsubscribe, &token), None);
+    }
+
+    #[test]
+    fn garbage_tokens_are_rejected() {
+        assert_eq!(links().verify(LinkPurpose::Unsubscribe, ""), None);
+        assert_eq!(
+            links().verify(LinkPurpose::Unsubscribe, "not a token"),
+            None
+        );
+    }
+
+    #[test]
+    fn links_point_to_the_route_for_their_purpose() {
+        let subscriber_id = Uuid::new_v4();
+        let link = links().link(LinkPurpose::Preferences, subscriber_id);
+        let token = link
+            .strip_prefix("http://127.0.0.1/subscriptions/preferences?token=")
+            .unwrap();
+        assert_eq!(
+            links().verify(LinkPurpose::Preferences, token),
+            Some(subscriber_id)
+        );
+    }
+}
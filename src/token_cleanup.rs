@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use sqlx::PgPool;
+
+/// Used and expired confirmation links are kept this long, so that a used link still reports
+/// that it was used and an expired one can still be exchanged for a new one.
+const TOKEN_RETENTION_DAYS: i32 = 7;
+
+/// Deletes stale subscription tokens periodically, until the process stops.
+pub async fn run_token_cleanup_until_stopped(pool: PgPool, interval: Duration) {
+    loop {
+        match delete_stale_tokens(&pool).await {
+            Ok(n_deleted) => tracing::info!(n_deleted, "Deleted stale subscription tokens"),
+            Err(error) => {
+                tracing::error!(error.cause_chain = ?error, "Failed to delete stale subscription tokens")
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Deletes the subscription tokens that were used, or expired unused, long ago. Returns how many
+/// were deleted.
+#[tracing::instrument(skip(pool))]
+pub async fn delete_stale_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE COALESCE(used_at, expires_at) < now() - make_interval(days => $1)
+        "#,
+        TOKEN_RETENTION_DAYS,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
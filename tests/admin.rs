@@ -0,0 +1,251 @@
+mod common;
+
+use actix_web::{
+    cookie::{time::Duration, Cookie, Key},
+    dev::{Service, ServiceResponse},
+    http::{self, header::LOCATION},
+    test, web, App,
+};
+use common::TestUser;
+use sqlx::PgPool;
+use zero2prod::{app_config, session_middleware, FlashMessagesFramework, PgSessionStore};
+
+async fn setup_app(
+    db_pool: &PgPool,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await
+}
+
+async fn log_in(
+    app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+    username: &str,
+    password: &str,
+) -> Cookie<'static> {
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form([("username", username), ("password", password)])
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/dashboard");
+    res.response()
+        .cookies()
+        .find(|c| c.name() == "id")
+        .unwrap()
+        .into_owned()
+}
+
+async fn change_password(
+    app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+    session_cookie: &Cookie<'static>,
+    current_password: &str,
+    new_password: &str,
+    new_password_check: &str,
+) -> ServiceResponse {
+    let req = test::TestRequest::post()
+        .uri("/admin/password")
+        .cookie(session_cookie.clone())
+        .set_form([
+            ("current_password", current_password),
+            ("new_password", new_password),
+            ("new_password_check", new_password_check),
+        ])
+        .to_request();
+    test::call_service(app, req).await
+}
+
+#[sqlx::test]
+async fn anonymous_users_are_redirected_to_login(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+
+    for uri in ["/admin/dashboard", "/admin/password"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+    }
+    let res = change_password(
+        &app,
+        &Cookie::new("id", "not-a-session"),
+        "password",
+        "a-new-password",
+        "a-new-password",
+    )
+    .await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn the_dashboard_greets_the_logged_in_user(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user.username, &user.password).await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/dashboard")
+        .cookie(session_cookie)
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains(&format!("Welcome {}", user.username)));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn invalid_password_changes_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user.username, &user.password).await;
+
+    let test_cases = [
+        (
+            "wrong-current-password",
+            "a-long-new-password",
+            "a-long-new-password",
+            "The current password is incorrect.",
+        ),
+        (
+            user.password.as_str(),
+            "a-long-new-password",
+            "another-new-password",
+            "You entered two different new passwords",
+        ),
+        (
+            user.password.as_str(),
+            "short",
+            "short",
+            "at least 12 characters",
+        ),
+    ];
+    for (current_password, new_password, new_password_check, error) in test_cases {
+        let res = change_password(
+            &app,
+            &session_cookie,
+            current_password,
+            new_password,
+            new_password_check,
+        )
+        .await;
+        assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/password");
+
+        let req = test::TestRequest::get()
+            .uri("/admin/password")
+            .cookie(session_cookie.clone())
+            .cookie(common::flash_cookie(&res).unwrap())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body)?;
+        assert!(body.contains(error), "Missing `{error}` in the page.");
+    }
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn changing_password_works(db_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user.username, &user.password).await;
+
+    let new_password = uuid::Uuid::new_v4().to_string();
+    let res = change_password(
+        &app,
+        &session_cookie,
+        &user.password,
+        &new_password,
+        &new_password,
+    )
+    .await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/password");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/password")
+        .cookie(session_cookie.clone())
+        .cookie(common::flash_cookie(&res).unwrap())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains(r#"<p class="flash-success"><i>Your password has been changed.</i></p>"#));
+
+    log_in(&app, &user.username, &new_password).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn logout_clears_the_session(db_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user.username, &user.password).await;
+
+    let req = test::TestRequest::post()
+        .uri("/admin/logout")
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+    let req = test::TestRequest::get()
+        .uri("/login")
+        .cookie(common::flash_cookie(&res).unwrap())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("You have successfully logged out."));
+
+    let req = test::TestRequest::get()
+        .uri("/admin/dashboard")
+        .cookie(session_cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn tampered_flash_messages_are_ignored(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = setup_app(&db_pool).await;
+
+    let forged = r#"[{"level":"error","content":"Forged message"}]"#;
+    let req = test::TestRequest::get()
+        .uri("/login")
+        .cookie(Cookie::new("_flash", forged))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(!body.contains("Forged message"));
+
+    Ok(())
+}
@@ -1,3 +1,10 @@
+#![allow(dead_code)]
+
+use actix_web::{cookie::Cookie, dev::ServiceResponse};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
+use sqlx::PgPool;
+use uuid::Uuid;
+
 pub fn extract_links(body: &str) -> Vec<String> {
     linkify::LinkFinder::new()
         .links(body)
@@ -7,3 +14,51 @@ pub fn extract_links(body: &str) -> Vec<String> {
         })
         .collect()
 }
+
+pub fn flash_cookie(res: &ServiceResponse) -> Option<Cookie<'static>> {
+    res.response()
+        .cookies()
+        .find(|c| c.name() == "_flash")
+        .map(|c| c.into_owned())
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn password_hash(&self) -> String {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+    }
+
+    pub async fn store(&self, db_pool: &PgPool) {
+        let password_hash = self.password_hash();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(db_pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
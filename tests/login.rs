@@ -0,0 +1,273 @@
+mod common;
+
+use actix_session::storage::SessionStore;
+use actix_web::{
+    cookie::{time::Duration, Key, SameSite},
+    http::{self, header::LOCATION},
+    test, web, App,
+};
+use common::TestUser;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use zero2prod::{
+    app_config, seed_admin_user, session_middleware, FlashMessagesFramework, PgSessionStore,
+};
+
+#[sqlx::test]
+async fn an_error_message_is_set_on_failure(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form([
+            ("username", "random-username"),
+            ("password", "random-password"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+    let flash_cookie = common::flash_cookie(&res).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/login")
+        .cookie(flash_cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    // The message is shown once: the cookie is cleared right away.
+    assert_eq!(
+        common::flash_cookie(&res).unwrap().max_age(),
+        Some(Duration::ZERO)
+    );
+    let body = test::read_body(res).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains(r#"<p class="flash-error"><i>Authentication failed</i></p>"#));
+
+    let req = test::TestRequest::get().uri("/login").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(!body.contains("Authentication failed"));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn login_with_a_wrong_password_is_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form([
+            ("username", user.username.as_str()),
+            ("password", "wrong-password"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert!(res
+        .headers()
+        .get(LOCATION)
+        .unwrap()
+        .to_str()?
+        .starts_with("/login"));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn login_with_valid_credentials_redirects_to_the_admin_dashboard(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form([
+            ("username", user.username.as_str()),
+            ("password", user.password.as_str()),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/dashboard");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn login_starts_a_new_session(db_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+
+    let login = |session_cookie: Option<actix_web::cookie::Cookie<'static>>| {
+        let mut req = test::TestRequest::post().uri("/login").set_form([
+            ("username", user.username.as_str()),
+            ("password", user.password.as_str()),
+        ]);
+        if let Some(cookie) = session_cookie {
+            req = req.cookie(cookie);
+        }
+        req.to_request()
+    };
+
+    let res = test::call_service(&app, login(None)).await;
+    let first_cookie = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == "id")
+        .unwrap()
+        .into_owned();
+    assert_eq!(first_cookie.http_only(), Some(true));
+    assert_eq!(first_cookie.same_site(), Some(SameSite::Strict));
+
+    let res = test::call_service(&app, login(Some(first_cookie.clone()))).await;
+    let second_cookie = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == "id")
+        .unwrap()
+        .into_owned();
+    assert_ne!(first_cookie.value(), second_cookie.value());
+
+    // The previous session is dropped when the key is rotated.
+    let n_sessions = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM sessions")
+        .fetch_one(&db_pool)
+        .await?
+        .count;
+    assert_eq!(n_sessions, 1);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn sessions_do_not_outlive_their_absolute_timeout(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let state = HashMap::from([("user_id".to_string(), "\"someone\"".to_string())]);
+    let session_key = session_store.save(state, &Duration::minutes(30)).await?;
+    assert!(session_store.load(&session_key).await?.is_some());
+
+    sqlx::query!(
+        "UPDATE sessions SET absolute_expires_at = now() - interval '1 second' WHERE session_key = $1",
+        session_key.as_ref(),
+    )
+    .execute(&db_pool)
+    .await?;
+    session_store
+        .update_ttl(&session_key, &Duration::minutes(30))
+        .await?;
+    assert!(session_store.load(&session_key).await?.is_none());
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn the_admin_user_is_seeded_from_a_password_hash_only(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone())),
+    )
+    .await;
+    let login = |password: &str| {
+        test::TestRequest::post()
+            .uri("/login")
+            .set_form([("username", "admin"), ("password", password)])
+            .to_request()
+    };
+
+    // There is no admin until one is seeded, not even with the local development password.
+    let res = test::call_service(&app, login("everythinghastostartsomewhere")).await;
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+    let admin = TestUser::generate();
+    seed_admin_user(&admin.password_hash(), &db_pool).await?;
+    let res = test::call_service(&app, login(&admin.password)).await;
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/dashboard");
+
+    // An existing admin is left untouched.
+    seed_admin_user(&TestUser::generate().password_hash(), &db_pool).await?;
+    let res = test::call_service(&app, login(&admin.password)).await;
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/dashboard");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn an_invalid_admin_password_hash_is_refused(db_pool: PgPool) {
+    assert!(seed_admin_user("everythinghastostartsomewhere", &db_pool)
+        .await
+        .is_err());
+}
@@ -1,15 +1,35 @@
 mod common;
 
 use actix_web::{
+    cookie::{time::Duration, Cookie, Key},
     dev::Service,
-    http::{self, header::ContentType},
+    http::{
+        self,
+        header::{ContentType, AUTHORIZATION, LOCATION, WWW_AUTHENTICATE},
+    },
     test, web, App,
 };
-use common::extract_links;
+use base64::Engine;
+use common::{extract_links, TestUser};
 use fake::{faker::internet::en::SafeEmail, Fake, Faker};
 use sqlx::PgPool;
-use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
-use zero2prod::{app_config, ApplicationBaseUrl, EmailClient, SubscriberEmail};
+use std::sync::Arc;
+use wiremock::{
+    matchers::{any, body_string_contains},
+    Mock, MockServer, ResponseTemplate,
+};
+use zero2prod::{
+    app_config, release_due_issues, session_middleware, try_execute_task, ApplicationBaseUrl,
+    EmailSender, ExecutionOutcome, FlashMessagesFramework, LinkPurpose, NewsletterTopics,
+    PgSessionStore, RetryPolicy, SendGridClient, SubscriberEmail, SubscriberLinks,
+};
+
+fn subscriber_links() -> SubscriberLinks {
+    SubscriberLinks::new(
+        "http://127.0.0.1".to_string(),
+        "subscriber-links-test-secret",
+    )
+}
 
 async fn setup_mocks(
     db_pool: &PgPool,
@@ -20,25 +40,108 @@ async fn setup_mocks(
         Error = actix_web::Error,
     >,
     MockServer,
+    Arc<dyn EmailSender>,
 ) {
     let mock_server = MockServer::start().await;
 
     let auth_token = Faker.fake();
     let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-    let email_client = EmailClient::new(mock_server.uri(), auth_token, from);
+    let email_client: Arc<dyn EmailSender> =
+        Arc::new(SendGridClient::new(mock_server.uri(), auth_token, from));
 
     let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let topics = NewsletterTopics(vec!["releases".to_string(), "tutorials".to_string()]);
 
+    let session_store = PgSessionStore::new(db_pool.clone(), Duration::hours(12));
+    let key = Key::generate();
     let app = test::init_service(
         App::new()
+            .wrap(FlashMessagesFramework::new(key.clone()))
+            .wrap(session_middleware(
+                session_store,
+                key,
+                Duration::minutes(30),
+            ))
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
-            .app_data(web::Data::new(app_base_url)),
+            .app_data(web::Data::from(email_client.clone()))
+            .app_data(web::Data::new(app_base_url))
+            .app_data(web::Data::new(subscriber_links()))
+            .app_data(web::Data::new(topics)),
     )
     .await;
 
-    (app, mock_server)
+    (app, mock_server, email_client)
+}
+
+const MAX_ATTEMPTS: u32 = 3;
+const BATCH_SIZE: u32 = 10;
+
+async fn dispatch_all_pending_emails(db_pool: &PgPool, email_client: &dyn EmailSender) {
+    // No backoff, so that retries are due right away.
+    let retry_policy = RetryPolicy {
+        max_attempts: MAX_ATTEMPTS,
+        base_delay: std::time::Duration::ZERO,
+        max_delay: std::time::Duration::ZERO,
+    };
+    let subscriber_links = subscriber_links();
+    loop {
+        if let ExecutionOutcome::EmptyQueue = try_execute_task(
+            db_pool,
+            email_client,
+            &retry_policy,
+            BATCH_SIZE,
+            &subscriber_links,
+        )
+        .await
+        .unwrap()
+        {
+            break;
+        }
+    }
+}
+
+/// What the `n`-th recipient of a SendGrid batch request receives: their headers, and the text
+/// and HTML content with their substitutions applied.
+fn personalized(request: &wiremock::Request, n: usize) -> (serde_json::Value, String, String) {
+    let body: serde_json::Value = request.body_json().unwrap();
+    let personalization = &body["personalizations"][n];
+    let substitute = |content: &serde_json::Value| {
+        let substitutions = personalization["substitutions"].as_object().unwrap();
+        substitutions.iter().fold(
+            content.as_str().unwrap().to_string(),
+            |content, (placeholder, value)| content.replace(placeholder, value.as_str().unwrap()),
+        )
+    };
+    (
+        personalization["headers"].clone(),
+        substitute(&body["content"][0]["value"]),
+        substitute(&body["content"][1]["value"]),
+    )
+}
+
+async fn publish_newsletter(
+    app: &impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    user: &TestUser,
+) {
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
 }
 
 async fn create_unconfirmed_subscriber(
@@ -84,11 +187,42 @@ async fn create_confirmed_subscriber(
     test::call_service(&app, req).await;
 }
 
+fn basic_auth(username: &str, password: &str) -> (http::header::HeaderName, String) {
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+    (AUTHORIZATION, format!("Basic {credentials}"))
+}
+
+async fn log_in(
+    app: &impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    user: &TestUser,
+) -> Cookie<'static> {
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form([
+            ("username", user.username.as_str()),
+            ("password", user.password.as_str()),
+        ])
+        .to_request();
+    let res = test::call_service(app, req).await;
+    res.response()
+        .cookies()
+        .find(|c| c.name() == "id")
+        .unwrap()
+        .into_owned()
+}
+
 #[sqlx::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers(
     db_pool: PgPool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (app, mock_server) = setup_mocks(&db_pool).await;
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
 
     create_unconfirmed_subscriber(&app, &mock_server).await;
 
@@ -108,10 +242,12 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers(
     });
     let req = test::TestRequest::post()
         .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
         .set_json(body)
         .to_request();
     let res = test::call_service(&app, req).await;
-    assert_eq!(res.status(), http::StatusCode::OK);
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
 
     Ok(())
 }
@@ -120,7 +256,9 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers(
 async fn newsletters_are_delivered_to_confirmed_subscribers(
     db_pool: PgPool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (app, mock_server) = setup_mocks(&db_pool).await;
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
 
     create_confirmed_subscriber(&app, &mock_server).await;
 
@@ -140,10 +278,1433 @@ async fn newsletters_are_delivered_to_confirmed_subscribers(
     });
     let req = test::TestRequest::post()
         .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
         .set_json(body)
         .to_request();
     let res = test::call_service(&app, req).await;
-    assert_eq!(res.status(), http::StatusCode::OK);
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM issue_delivery_queue")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(queued.count, 1);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn requests_missing_authorization_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        res.headers().get(WWW_AUTHENTICATE).unwrap(),
+        r#"Basic realm="publish""#
+    );
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn non_existing_user_is_rejected(db_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let username: String = Faker.fake();
+    let password: String = Faker.fake();
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&username, &password))
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        res.headers().get(WWW_AUTHENTICATE).unwrap(),
+        r#"Basic realm="publish""#
+    );
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn invalid_password_is_rejected(db_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let password: String = Faker.fake();
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &password))
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        res.headers().get(WWW_AUTHENTICATE).unwrap(),
+        r#"Basic realm="publish""#
+    );
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn anonymous_users_cannot_publish_from_the_admin_form(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, _) = setup_mocks(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri("/admin/newsletters")
+        .set_form([
+            ("title", "Newsletter title"),
+            ("text_content", "Newsletter body as plain text"),
+            ("html_content", "<p>Newsletter body as HTML</p>"),
+            ("idempotency_key", &uuid::Uuid::new_v4().to_string()),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn newsletters_published_from_the_admin_form_are_delivered(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .named("email is sent")
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri("/admin/newsletters")
+        .cookie(session_cookie.clone())
+        .set_form([
+            ("title", "Newsletter title"),
+            ("text_content", "Newsletter body as plain text"),
+            ("html_content", "<p>Newsletter body as HTML</p>"),
+            ("idempotency_key", &uuid::Uuid::new_v4().to_string()),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/newsletters");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/newsletters")
+        .cookie(session_cookie)
+        .cookie(common::flash_cookie(&res).unwrap())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("The newsletter issue has been accepted - emails will go out shortly."));
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn newsletter_creation_is_idempotent(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .named("email is sent once")
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri("/admin/newsletters")
+            .cookie(session_cookie.clone())
+            .set_form([
+                ("title", "Newsletter title"),
+                ("text_content", "Newsletter body as plain text"),
+                ("html_content", "<p>Newsletter body as HTML</p>"),
+                ("idempotency_key", &idempotency_key),
+            ])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/admin/newsletters");
+
+        let req = test::TestRequest::get()
+            .uri("/admin/newsletters")
+            .cookie(session_cookie.clone())
+            .cookie(common::flash_cookie(&res).unwrap())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body)?;
+        assert!(
+            body.contains("The newsletter issue has been accepted - emails will go out shortly.")
+        );
+    }
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn retried_api_requests_are_not_delivered_twice(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .named("email is sent once")
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let request = || {
+        test::TestRequest::post()
+            .uri("/newsletters")
+            .insert_header(basic_auth(&user.username, &user.password))
+            .insert_header(("Idempotency-Key", idempotency_key.as_str()))
+            .set_json(&body)
+            .to_request()
+    };
+    let (first, second) = tokio::join!(
+        test::call_service(&app, request()),
+        test::call_service(&app, request())
+    );
+    assert_eq!(first.status(), http::StatusCode::ACCEPTED);
+    assert_eq!(second.status(), http::StatusCode::ACCEPTED);
+
+    let res = test::call_service(&app, request()).await;
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn transient_delivery_failures_are_retried(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .named("first attempt fails")
+        .mount(&mock_server)
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .named("retry succeeds")
+        .mount(&mock_server)
+        .await;
+
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM issue_delivery_queue")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(queued.count, 0);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn rate_limited_deliveries_are_retried_no_sooner_than_retry_after(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+        .expect(1)
+        .named("rate limited for an hour")
+        .mount(&mock_server)
+        .await;
+
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let task = sqlx::query!("SELECT status, n_retries, execute_after FROM issue_delivery_queue")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(task.status, "pending");
+    assert_eq!(task.n_retries, 1);
+    assert!(task.execute_after > time::OffsetDateTime::now_utc() + time::Duration::minutes(59));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn deliveries_are_dead_lettered_after_the_maximum_number_of_attempts(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(429))
+        .expect(u64::from(MAX_ATTEMPTS))
+        .named("every attempt is rate limited")
+        .mount(&mock_server)
+        .await;
+
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let task = sqlx::query!("SELECT status, n_retries FROM issue_delivery_queue")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(task.status, "dead_letter");
+    assert_eq!(task.n_retries, MAX_ATTEMPTS as i32);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn permanent_delivery_failures_are_dead_lettered_and_can_be_redriven(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    let rejection = Mock::given(any())
+        .respond_with(ResponseTemplate::new(400))
+        .expect(1)
+        .named("delivery is rejected without retries")
+        .mount_as_scoped(&mock_server)
+        .await;
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+    drop(rejection);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/deliveries/dead_letters")
+        .cookie(session_cookie.clone())
+        .to_request();
+    let dead_letters: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(
+        dead_letters[0]["subscriber_email"],
+        "ursula_le_guin@gmail.com"
+    );
+    assert_eq!(dead_letters[0]["n_attempts"], 1);
+
+    // A malformed or misspelled filter is rejected rather than taken for no filter at all.
+    let req = test::TestRequest::post()
+        .uri("/admin/deliveries/dead_letters/redrive")
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "newsletter_issue_id": "not-a-uuid" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+    let req = test::TestRequest::post()
+        .uri("/admin/deliveries/dead_letters/redrive")
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "email": "someone@example.com" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+    let n_dead_letters = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue WHERE status = 'dead_letter'"#
+    )
+    .fetch_one(&db_pool)
+    .await?
+    .count;
+    assert_eq!(n_dead_letters, 1);
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .named("re-driven delivery succeeds")
+        .mount(&mock_server)
+        .await;
+    let req = test::TestRequest::post()
+        .uri("/admin/deliveries/dead_letters/redrive")
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "subscriber_email": "ursula_le_guin@gmail.com" }))
+        .to_request();
+    let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res["redriven"], 1);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/deliveries/dead_letters")
+        .cookie(session_cookie)
+        .to_request();
+    let dead_letters: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(dead_letters, serde_json::json!([]));
+
+    Ok(())
+}
+
+async fn insert_confirmed_subscriber(db_pool: &PgPool, email: &str) {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, 'subscriber', now(), 'confirmed')
+        "#,
+        uuid::Uuid::new_v4(),
+        email,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+}
+
+async fn set_preferences(db_pool: &PgPool, email: &str, topics: &[&str], digest_frequency: &str) {
+    let topics: Vec<String> = topics.iter().map(|topic| topic.to_string()).collect();
+    sqlx::query!(
+        "UPDATE subscriptions SET topics = $2, digest_frequency = $3 WHERE email = $1",
+        email,
+        &topics,
+        digest_frequency,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+}
+
+async fn queued_recipients(db_pool: &PgPool) -> Vec<String> {
+    sqlx::query!("SELECT subscriber_email FROM issue_deliveries ORDER BY subscriber_email")
+        .fetch_all(db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.subscriber_email)
+        .collect()
+}
+
+#[sqlx::test]
+async fn issues_with_a_topic_only_reach_the_subscribers_following_it(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    insert_confirmed_subscriber(&db_pool, "releases@example.com").await;
+    set_preferences(&db_pool, "releases@example.com", &["releases"], "immediate").await;
+    insert_confirmed_subscriber(&db_pool, "tutorials@example.com").await;
+    set_preferences(
+        &db_pool,
+        "tutorials@example.com",
+        &["tutorials"],
+        "immediate",
+    )
+    .await;
+    insert_confirmed_subscriber(&db_pool, "everything@example.com").await;
+
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
+        .set_json(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "topic": "releases",
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+
+    assert_eq!(
+        queued_recipients(&db_pool).await,
+        ["everything@example.com", "releases@example.com"]
+    );
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issues_with_an_unknown_topic_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    insert_confirmed_subscriber(&db_pool, "subscriber@example.com").await;
+
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
+        .set_json(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "topic": "gossip",
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+    assert!(queued_recipients(&db_pool).await.is_empty());
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn digest_subscribers_receive_issues_at_the_start_of_the_next_period(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    insert_confirmed_subscriber(&db_pool, "immediate@example.com").await;
+    insert_confirmed_subscriber(&db_pool, "daily@example.com").await;
+    set_preferences(&db_pool, "daily@example.com", &[], "daily").await;
+    insert_confirmed_subscriber(&db_pool, "weekly@example.com").await;
+    set_preferences(&db_pool, "weekly@example.com", &[], "weekly").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let request = &mock_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = request.body_json()?;
+    assert_eq!(body["personalizations"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        body["personalizations"][0]["to"][0]["email"],
+        "immediate@example.com"
+    );
+
+    let held = sqlx::query!(
+        r#"
+        SELECT
+            subscriber_email,
+            execute_after = date_trunc('day', now()) + interval '1 day' AS "next_day!",
+            execute_after = date_trunc('week', now()) + interval '1 week' AS "next_week!"
+        FROM issue_delivery_queue
+        WHERE status = 'pending'
+        ORDER BY subscriber_email
+        "#
+    )
+    .fetch_all(&db_pool)
+    .await?;
+    assert_eq!(held.len(), 2);
+    assert_eq!(held[0].subscriber_email, "daily@example.com");
+    assert!(held[0].next_day);
+    assert_eq!(held[1].subscriber_email, "weekly@example.com");
+    assert!(held[1].next_week);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issue_report_counts_deliveries_and_lists_failures(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user).await;
+    insert_confirmed_subscriber(&db_pool, "delivered@example.com").await;
+    insert_confirmed_subscriber(&db_pool, "rejected@example.com").await;
+    insert_confirmed_subscriber(&db_pool, "not an email").await;
+
+    // Recipients are batched by address, after the invalid one is skipped.
+    Mock::given(body_string_contains("rejected@example.com"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+            "errors": [{
+                "message": "Does not contain a valid address.",
+                "field": "personalizations.1.to.0.email",
+            }]
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    publish_newsletter(&app, &user).await;
+    let issue = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&db_pool)
+        .await?;
+    let report_uri = format!("/admin/newsletters/{}", issue.newsletter_issue_id);
+
+    let req = test::TestRequest::get()
+        .uri(&report_uri)
+        .cookie(session_cookie.clone())
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        report["counts"],
+        serde_json::json!({ "queued": 3, "sent": 0, "failed": 0, "skipped": 0 })
+    );
+
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let req = test::TestRequest::get()
+        .uri(&report_uri)
+        .cookie(session_cookie.clone())
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["title"], "Newsletter title");
+    assert_eq!(
+        report["counts"],
+        serde_json::json!({ "queued": 0, "sent": 1, "failed": 1, "skipped": 1 })
+    );
+    assert_eq!(report["failures"]["total"], 2);
+    let failures = report["failures"]["items"].as_array().unwrap();
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0]["subscriber_email"], "not an email");
+    assert_eq!(failures[0]["status"], "skipped");
+    assert_eq!(failures[1]["subscriber_email"], "rejected@example.com");
+    assert_eq!(failures[1]["status"], "failed");
+    assert!(failures[1]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Does not contain a valid address."));
+
+    let req = test::TestRequest::get()
+        .uri(&format!("{report_uri}?page=2&page_size=1"))
+        .cookie(session_cookie)
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let failures = report["failures"]["items"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0]["subscriber_email"], "rejected@example.com");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issue_report_for_unknown_issue_is_not_found(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{}", uuid::Uuid::new_v4()))
+        .cookie(session_cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+async fn schedule_newsletter(
+    app: &impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    user: &TestUser,
+    scheduled_for: &str,
+) -> serde_json::Value {
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "scheduled_for": scheduled_for,
+    });
+    let req = test::TestRequest::post()
+        .uri("/newsletters")
+        .insert_header(basic_auth(&user.username, &user.password))
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+    test::read_body_json(res).await
+}
+
+#[sqlx::test]
+async fn scheduled_issues_are_not_delivered_before_they_are_due(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .named("email is not sent yet")
+        .mount(&mock_server)
+        .await;
+
+    let issue = schedule_newsletter(&app, &user, "2999-01-05T09:00:00+01:00").await;
+    assert_eq!(issue["status"], "scheduled");
+
+    assert_eq!(release_due_issues(&db_pool).await?, 0);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn rescheduled_issues_are_released_once_due(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .named("email is sent once due")
+        .mount(&mock_server)
+        .await;
+
+    let issue = schedule_newsletter(&app, &user, "2999-01-05T09:00:00+01:00").await;
+    let issue_uri = format!(
+        "/admin/newsletters/{}",
+        issue["newsletter_issue_id"].as_str().unwrap()
+    );
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{issue_uri}/reschedule"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "scheduled_for": "2020-01-01T09:00:00Z" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+
+    assert_eq!(release_due_issues(&db_pool).await?, 1);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let req = test::TestRequest::get()
+        .uri(&issue_uri)
+        .cookie(session_cookie)
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["status"], "sent");
+    assert_eq!(report["scheduled_for"], "2020-01-01T09:00:00Z");
+    assert_eq!(report["counts"]["sent"], 1);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn cancelled_issues_are_never_delivered(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .named("email is never sent")
+        .mount(&mock_server)
+        .await;
+
+    let issue = schedule_newsletter(&app, &user, "2999-01-05T09:00:00+01:00").await;
+    let issue_uri = format!(
+        "/admin/newsletters/{}",
+        issue["newsletter_issue_id"].as_str().unwrap()
+    );
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{issue_uri}/cancel"))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{issue_uri}/cancel"))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{issue_uri}/reschedule"))
+        .cookie(session_cookie)
+        .set_json(serde_json::json!({ "scheduled_for": "2020-01-01T09:00:00Z" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+
+    assert_eq!(release_due_issues(&db_pool).await?, 0);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issues_can_be_scheduled_from_the_admin_form_in_a_timezone(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    for (timezone, expected_message) in [
+        (
+            "Europe/Paris",
+            "The newsletter issue has been scheduled for 2999-01-05T09:00:00+01:00.",
+        ),
+        (
+            "Mars/Olympus_Mons",
+            "Mars/Olympus_Mons is not a known timezone.",
+        ),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/admin/newsletters")
+            .cookie(session_cookie.clone())
+            .set_form([
+                ("title", "Newsletter title"),
+                ("text_content", "Newsletter body as plain text"),
+                ("html_content", "<p>Newsletter body as HTML</p>"),
+                ("idempotency_key", &uuid::Uuid::new_v4().to_string()),
+                ("scheduled_for", "2999-01-05T09:00"),
+                ("timezone", timezone),
+            ])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/newsletters")
+            .cookie(session_cookie.clone())
+            .cookie(common::flash_cookie(&res).unwrap())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body)?;
+        assert!(body.contains(expected_message), "{body}");
+    }
+
+    let issue = sqlx::query!("SELECT status, scheduled_for FROM newsletter_issues")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(issue.status, "scheduled");
+    assert_eq!(
+        issue.scheduled_for,
+        Some(time::macros::datetime!(2999-01-05 08:00 UTC))
+    );
+
+    Ok(())
+}
+
+async fn create_draft(
+    app: &impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    session_cookie: &Cookie<'static>,
+) -> String {
+    let req = test::TestRequest::post()
+        .uri("/admin/newsletters/drafts")
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({
+            "title": "Draft title",
+            "text_content": "Draft body as plain text",
+            "html_content": "<p>Draft body as HTML</p>",
+        }))
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CREATED);
+    let draft: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(draft["status"], "draft");
+    draft["newsletter_issue_id"].as_str().unwrap().to_string()
+}
+
+#[sqlx::test]
+async fn drafts_can_be_created_edited_listed_and_discarded(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .named("drafts are not delivered")
+        .mount(&mock_server)
+        .await;
+
+    let draft_id = create_draft(&app, &session_cookie).await;
+    let kept_draft_id = create_draft(&app, &session_cookie).await;
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({
+            "title": "Edited title",
+            "text_content": "Edited body as plain text",
+            "html_content": "<p>Edited body as HTML</p>",
+        }))
+        .to_request();
+    let draft: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(draft["title"], "Edited title");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/newsletters/drafts")
+        .cookie(session_cookie.clone())
+        .to_request();
+    let drafts: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let drafts = drafts.as_array().unwrap();
+    assert_eq!(drafts.len(), 2);
+    assert_eq!(drafts[0]["newsletter_issue_id"], draft_id.as_str());
+    assert_eq!(drafts[0]["text_content"], "Edited body as plain text");
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/newsletters/drafts")
+        .cookie(session_cookie)
+        .to_request();
+    let drafts: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let drafts = drafts.as_array().unwrap();
+    assert_eq!(drafts.len(), 1);
+    assert_eq!(drafts[0]["newsletter_issue_id"], kept_draft_id.as_str());
+
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn previews_match_what_recipients_receive(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{draft_id}/preview"))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let html_preview = String::from_utf8(test::read_body(res).await.to_vec())?;
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/admin/newsletters/{draft_id}/preview?format=text"
+        ))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+        "text/plain; charset=utf-8"
+    );
+    let text_preview = String::from_utf8(test::read_body(res).await.to_vec())?;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/newsletters/{draft_id}/publish"))
+        .cookie(session_cookie)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::ACCEPTED);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    // Previews carry a sample unsubscribe link, recipients their own.
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .id;
+    let (mut text_preview, mut html_preview) = (text_preview, html_preview);
+    for purpose in [LinkPurpose::Preferences, LinkPurpose::Unsubscribe] {
+        let sample_link = subscriber_links().link(purpose, uuid::Uuid::nil());
+        let link = subscriber_links().link(purpose, subscriber_id);
+        text_preview = text_preview.replace(&sample_link, &link);
+        html_preview = html_preview.replace(&sample_link, &link);
+    }
+    let received = mock_server.received_requests().await.unwrap();
+    let (_, text, html) = personalized(received.last().unwrap(), 0);
+    assert_eq!(text, text_preview);
+    assert_eq!(html, html_preview);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn publishing_with_a_malformed_body_is_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/newsletters/{draft_id}/publish"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "scheduled_for": "2023-04-11 09:00" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/newsletters/{draft_id}/publish"))
+        .cookie(session_cookie.clone())
+        .set_form([("scheduled_for", "2999-01-05T08:00:00Z")])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie)
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["status"], "draft");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn publishing_with_an_unknown_field_is_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/newsletters/{draft_id}/publish"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({ "schedule_for": "2999-01-05T08:00:00Z" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie)
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["status"], "draft");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn sent_issues_cannot_be_published_or_edited_again(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .named("email is sent once")
+        .mount(&mock_server)
+        .await;
+
+    let publish = || {
+        test::TestRequest::post()
+            .uri(&format!("/admin/newsletters/{draft_id}/publish"))
+            .cookie(session_cookie.clone())
+            .to_request()
+    };
+    let issue: serde_json::Value = test::call_and_read_body_json(&app, publish()).await;
+    assert_eq!(issue["status"], "sending");
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie.clone())
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["status"], "sent");
+
+    let res = test::call_service(&app, publish()).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({
+            "title": "Edited title",
+            "text_content": "Edited body as plain text",
+            "html_content": "<p>Edited body as HTML</p>",
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_copies_are_only_sent_to_the_given_addresses(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    Mock::given(body_string_contains("[TEST] Draft title"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .named("test copies are sent")
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/newsletters/{draft_id}/test"))
+        .cookie(session_cookie.clone())
+        .set_json(serde_json::json!({
+            "addresses": ["editor@example.com", "reviewer@example.com"],
+        }))
+        .to_request();
+    let results: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(results[0]["address"], "editor@example.com");
+    assert_eq!(results[0]["sent"], true);
+    assert_eq!(results[1]["address"], "reviewer@example.com");
+    assert_eq!(results[1]["sent"], true);
+
+    let received = mock_server.received_requests().await.unwrap();
+    let recipients = received
+        .iter()
+        .skip(1) // The subscriber's confirmation email.
+        .map(|r| {
+            let body: serde_json::Value = r.body_json().unwrap();
+            body["personalizations"][0]["to"][0]["email"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(recipients, ["editor@example.com", "reviewer@example.com"]);
+
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+    let deliveries = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM issue_deliveries")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(deliveries.count, 0);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/newsletters/{draft_id}"))
+        .cookie(session_cookie)
+        .to_request();
+    let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report["status"], "draft");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_copies_to_invalid_addresses_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, _) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let session_cookie = log_in(&app, &user).await;
+    let draft_id = create_draft(&app, &session_cookie).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+
+    for addresses in [
+        serde_json::json!(["editor@example.com", "not an email"]),
+        serde_json::json!([]),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/newsletters/{draft_id}/test"))
+            .cookie(session_cookie.clone())
+            .set_json(serde_json::json!({ "addresses": addresses }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issues_carry_a_one_click_unsubscribe_link(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .id;
+    let link = subscriber_links().link(LinkPurpose::Unsubscribe, subscriber_id);
+    let received = mock_server.received_requests().await.unwrap();
+    let (headers, text, html) = personalized(received.last().unwrap(), 0);
+    assert_eq!(headers["List-Unsubscribe"], format!("<{link}>"));
+    assert_eq!(
+        headers["List-Unsubscribe-Post"],
+        "List-Unsubscribe=One-Click"
+    );
+    assert!(text.contains(&link));
+    assert!(html.contains(&link));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn issues_are_sent_to_many_subscribers_in_a_single_request(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    let addresses = ["a@example.com", "b@example.com", "c@example.com"];
+    for address in addresses {
+        insert_confirmed_subscriber(&db_pool, address).await;
+    }
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    let request = &mock_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = request.body_json()?;
+    assert_eq!(body["personalizations"].as_array().unwrap().len(), 3);
+    for (n, address) in addresses.into_iter().enumerate() {
+        assert_eq!(body["personalizations"][n]["to"][0]["email"], address);
+        let subscriber_id = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", address)
+            .fetch_one(&db_pool)
+            .await?
+            .id;
+        let link = subscriber_links().link(LinkPurpose::Unsubscribe, subscriber_id);
+        let (headers, text, html) = personalized(request, n);
+        assert_eq!(headers["List-Unsubscribe"], format!("<{link}>"));
+        assert!(text.contains(&link));
+        assert!(html.contains(&link));
+    }
+
+    let sent =
+        sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_deliveries WHERE status = 'sent'"#)
+            .fetch_one(&db_pool)
+            .await?
+            .count;
+    assert_eq!(sent, 3);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn unsubscribed_subscribers_no_longer_receive_issues(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, email_client) = setup_mocks(&db_pool).await;
+    let user = TestUser::generate();
+    user.store(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .id;
+    let link = subscriber_links().link(LinkPurpose::Unsubscribe, subscriber_id);
+    let path = link.strip_prefix("http://127.0.0.1").unwrap();
+
+    // Opening the link only asks for confirmation.
+    let req = test::TestRequest::get().uri(path).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains(r#"method="post""#));
+    let status = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .status;
+    assert_eq!(status, "confirmed");
+
+    // Mail clients post the RFC 8058 one-click body to the same link.
+    let req = test::TestRequest::post()
+        .uri(path)
+        .insert_header(ContentType::form_url_encoded())
+        .set_payload("List-Unsubscribe=One-Click")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let status = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .status;
+    assert_eq!(status, "unsubscribed");
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+    publish_newsletter(&app, &user).await;
+    dispatch_all_pending_emails(&db_pool, email_client.as_ref()).await;
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn unsubscribe_requests_with_a_forged_token_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server, _) = setup_mocks(&db_pool).await;
+    create_confirmed_subscriber(&app, &mock_server).await;
+
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .id;
+    let forged = SubscriberLinks::new("http://127.0.0.1".to_string(), "another-secret");
+    let link = forged.link(LinkPurpose::Unsubscribe, subscriber_id);
+    let path = link.strip_prefix("http://127.0.0.1").unwrap();
+
+    let req = test::TestRequest::get().uri(path).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::post().uri(path).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+    let status = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .status;
+    assert_eq!(status, "confirmed");
 
     Ok(())
 }
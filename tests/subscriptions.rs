@@ -6,16 +6,17 @@ use actix_web::{
 };
 use fake::{faker::internet::en::SafeEmail, Fake, Faker};
 use sqlx::PgPool;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
-use zero2prod::{app_config, ApplicationBaseUrl, EmailClient, SubscriberEmail};
+use zero2prod::{app_config, ApplicationBaseUrl, EmailSender, SendGridClient, SubscriberEmail};
 
-async fn get_mock_client() -> (EmailClient, MockServer) {
+async fn get_mock_client() -> (Arc<dyn EmailSender>, MockServer) {
     let mock_server = MockServer::start().await;
 
     let auth_token = Faker.fake();
     let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-    let email_client = EmailClient::new(mock_server.uri(), auth_token, from);
+    let email_client = Arc::new(SendGridClient::new(mock_server.uri(), auth_token, from));
 
     Mock::given(any())
         .respond_with(ResponseTemplate::new(200))
@@ -35,7 +36,7 @@ async fn subscribe_returns_a_200_for_valid_form_data(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -63,7 +64,7 @@ async fn subscribe_persists_the_new_subscriber(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -96,7 +97,7 @@ async fn subscribe_returns_a_400_when_data_is_missing(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -133,7 +134,7 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_empty(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -170,7 +171,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -202,12 +203,12 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error(
             .wrap(TracingLogger::default())
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(web::Data::from(email_client))
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
 
-    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token_hash;",)
         .execute(&db_pool)
         .await
         .unwrap();
@@ -224,3 +225,194 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error(
 
     Ok(())
 }
+
+async fn subscribe_ursula(
+    app: &impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> http::StatusCode {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let req = test::TestRequest::post()
+        .uri("/subscriptions")
+        .insert_header(ContentType::form_url_encoded())
+        .set_payload(body)
+        .to_request();
+    test::call_service(app, req).await.status()
+}
+
+fn confirmation_link_path(email_request: &wiremock::Request) -> String {
+    let body = std::str::from_utf8(&email_request.body).unwrap();
+    let confirmation_link = common::extract_links(body)[0].clone();
+    confirmation_link
+        .strip_prefix("http://127.0.0.1")
+        .unwrap()
+        .to_string()
+}
+
+#[sqlx::test]
+async fn subscribing_again_while_pending_sends_a_new_confirmation_link(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (email_client, mock_server) = get_mock_client().await;
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::from(email_client))
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    assert_eq!(subscribe_ursula(&app).await, http::StatusCode::OK);
+    assert_eq!(subscribe_ursula(&app).await, http::StatusCode::OK);
+
+    let email_requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let (first_link, second_link) = (
+        confirmation_link_path(&email_requests[0]),
+        confirmation_link_path(&email_requests[1]),
+    );
+    assert_ne!(first_link, second_link);
+
+    let req = test::TestRequest::get().uri(&first_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::get().uri(&second_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "confirmed");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn concurrent_first_subscriptions_to_an_address_both_succeed(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (email_client, _) = get_mock_client().await;
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::from(email_client))
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    // Another request has inserted the address but not committed yet.
+    let mut transaction = db_pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now(), 'pending_confirmation')
+        "#,
+        uuid::Uuid::new_v4(),
+    )
+    .execute(&mut transaction)
+    .await?;
+    let commit_later = async {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(200)).await;
+        transaction.commit().await
+    };
+
+    let (status, committed) = tokio::join!(subscribe_ursula(&app), commit_later);
+    committed?;
+    assert_eq!(status, http::StatusCode::OK);
+
+    let n_subscriptions = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&db_pool)
+        .await?
+        .count;
+    assert_eq!(n_subscriptions, 1);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn subscribing_a_confirmed_address_sends_a_notice_instead(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (email_client, mock_server) = get_mock_client().await;
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::from(email_client))
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe_ursula(&app).await;
+    let email_requests = mock_server.received_requests().await.unwrap();
+    let req = test::TestRequest::get()
+        .uri(&confirmation_link_path(&email_requests[0]))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    assert_eq!(subscribe_ursula(&app).await, http::StatusCode::OK);
+
+    let email_requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let notice: serde_json::Value = email_requests[1].body_json()?;
+    assert_eq!(
+        notice["personalizations"][0]["subject"],
+        "You are already subscribed"
+    );
+    assert!(common::extract_links(std::str::from_utf8(&email_requests[1].body)?).is_empty());
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "confirmed");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn unsubscribed_addresses_subscribe_again_through_double_opt_in(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (email_client, mock_server) = get_mock_client().await;
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::from(email_client))
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe_ursula(&app).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&db_pool)
+        .await?;
+
+    assert_eq!(subscribe_ursula(&app).await, http::StatusCode::OK);
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "pending_confirmation");
+
+    let email_requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let req = test::TestRequest::get()
+        .uri(&confirmation_link_path(&email_requests[1]))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "confirmed");
+
+    Ok(())
+}
@@ -4,37 +4,33 @@ use actix_web::{
     http::{self, header::ContentType},
     test, web, App,
 };
-use fake::{faker::internet::en::SafeEmail, Fake, Faker};
+use fake::{faker::internet::en::SafeEmail, Fake};
 use sqlx::PgPool;
-use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
-use zero2prod::{app_config, ApplicationBaseUrl, EmailClient, SubscriberEmail};
-
-async fn get_mock_client() -> (EmailClient, MockServer) {
-    let mock_server = MockServer::start().await;
+use std::sync::Arc;
+use zero2prod::{
+    app_config, delete_stale_tokens, ApplicationBaseUrl, EmailSender, OutboxClient, OutboxEmail,
+    SubscriberEmail,
+};
 
-    let auth_token = Faker.fake();
+/// An outbox keeping the emails sent by the app, and the sender to register in the app.
+fn test_outbox() -> (Arc<OutboxClient>, web::Data<dyn EmailSender>) {
     let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
-    let email_client = EmailClient::new(mock_server.uri(), auth_token, from);
-
-    Mock::given(any())
-        .respond_with(ResponseTemplate::new(200))
-        .mount(&mock_server)
-        .await;
-
-    (email_client, mock_server)
+    let outbox = Arc::new(OutboxClient::new(from, None));
+    let email_sender: Arc<dyn EmailSender> = outbox.clone();
+    (outbox, web::Data::from(email_sender))
 }
 
 #[sqlx::test]
 async fn confirmations_without_token_are_rejected_with_a_400(
     db_pool: PgPool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (email_client, _) = get_mock_client().await;
+    let (_, email_sender) = test_outbox();
     let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
     let app = test::init_service(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(email_sender)
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -53,13 +49,13 @@ async fn confirmations_without_token_are_rejected_with_a_400(
 async fn the_link_returned_by_subscribe_returned_a_200_if_called(
     db_pool: PgPool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (email_client, mock_server) = get_mock_client().await;
+    let (outbox, email_sender) = test_outbox();
     let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
     let app = test::init_service(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(email_sender)
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -72,9 +68,7 @@ async fn the_link_returned_by_subscribe_returned_a_200_if_called(
         .to_request();
     test::call_service(&app, req).await;
 
-    let email_request = &mock_server.received_requests().await.unwrap()[0];
-    let body = std::str::from_utf8(&email_request.body)?;
-    let confirmation_link = common::extract_links(body)[0].clone();
+    let confirmation_link = common::extract_links(&outbox.emails()[0].text)[0].clone();
     let link_uri = confirmation_link
         .split('/')
         .skip(3)
@@ -94,13 +88,13 @@ async fn the_link_returned_by_subscribe_returned_a_200_if_called(
 async fn the_link_returned_by_subscribe_confirms_a_subscriber(
     db_pool: PgPool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (email_client, mock_server) = get_mock_client().await;
+    let (outbox, email_sender) = test_outbox();
     let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
     let app = test::init_service(
         App::new()
             .configure(app_config)
             .app_data(web::Data::new(db_pool.clone()))
-            .app_data(web::Data::new(email_client))
+            .app_data(email_sender)
             .app_data(web::Data::new(app_base_url)),
     )
     .await;
@@ -113,9 +107,7 @@ async fn the_link_returned_by_subscribe_confirms_a_subscriber(
         .to_request();
     test::call_service(&app, req).await;
 
-    let email_request = &mock_server.received_requests().await.unwrap()[0];
-    let body = std::str::from_utf8(&email_request.body)?;
-    let confirmation_link = common::extract_links(body)[0].clone();
+    let confirmation_link = common::extract_links(&outbox.emails()[0].text)[0].clone();
     let link_uri = confirmation_link
         .split('/')
         .skip(3)
@@ -135,3 +127,320 @@ async fn the_link_returned_by_subscribe_confirms_a_subscriber(
 
     Ok(())
 }
+
+async fn subscribe(
+    app: &impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let req = test::TestRequest::post()
+        .uri("/subscriptions")
+        .insert_header(ContentType::form_url_encoded())
+        .set_payload(body)
+        .to_request();
+    test::call_service(app, req).await;
+}
+
+fn confirmation_link_path(email: &OutboxEmail) -> String {
+    let confirmation_link = common::extract_links(&email.text)[0].clone();
+    confirmation_link
+        .strip_prefix("http://127.0.0.1")
+        .unwrap()
+        .to_string()
+}
+
+#[sqlx::test]
+async fn following_a_confirmation_link_again_is_harmless(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    let link = confirmation_link_path(&outbox.emails()[0]);
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let confirmed_at = sqlx::query!("SELECT confirmed_at FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?
+        .confirmed_at
+        .expect("The confirmation time was not recorded");
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains("Your subscription is already confirmed"));
+    let record = sqlx::query!("SELECT status, confirmed_at FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "confirmed");
+    assert_eq!(record.confirmed_at, Some(confirmed_at));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn expired_confirmation_links_are_rejected_and_can_be_renewed(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 hour'")
+        .execute(&db_pool)
+        .await?;
+    let expired_link = confirmation_link_path(&outbox.emails()[0]);
+
+    let req = test::TestRequest::get().uri(&expired_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::GONE);
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains("This confirmation link has expired."));
+
+    // The page offers to send a new link.
+    let resend_link =
+        expired_link.replace("/subscriptions/confirm?", "/subscriptions/confirm/resend?");
+    assert!(body.contains(&format!(r#"<form action="{resend_link}" method="post">"#)));
+    let req = test::TestRequest::post().uri(&resend_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let emails = outbox.emails();
+    assert_eq!(emails.len(), 2);
+    let new_link = confirmation_link_path(&emails[1]);
+    let req = test::TestRequest::get().uri(&expired_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::get().uri(&new_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "confirmed");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn valid_confirmation_links_are_not_renewed(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    let link = confirmation_link_path(&outbox.emails()[0]);
+
+    let resend_link = link.replace("/subscriptions/confirm?", "/subscriptions/confirm/resend?");
+    let req = test::TestRequest::post().uri(&resend_link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+    assert_eq!(outbox.emails().len(), 1);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn long_used_and_long_expired_tokens_are_cleaned_up(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber_id = uuid::Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now(), 'pending_confirmation')
+        "#,
+        subscriber_id,
+    )
+    .execute(&db_pool)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens
+            (subscription_token_hash, subscriber_id, created_at, expires_at, used_at)
+        VALUES
+            ('valid', $1, now(), now() + interval '1 day', NULL),
+            ('recently-expired', $1, now() - interval '2 days', now() - interval '1 day', NULL),
+            ('long-expired', $1, now() - interval '31 days', now() - interval '30 days', NULL),
+            ('recently-used', $1, now(), now() + interval '1 day', now()),
+            ('long-used', $1, now() - interval '31 days', now() - interval '30 days',
+                now() - interval '30 days')
+        "#,
+        subscriber_id,
+    )
+    .execute(&db_pool)
+    .await?;
+
+    assert_eq!(delete_stale_tokens(&db_pool).await?, 2);
+
+    let remaining = sqlx::query!(
+        "SELECT subscription_token_hash FROM subscription_tokens ORDER BY subscription_token_hash"
+    )
+    .fetch_all(&db_pool)
+    .await?
+    .into_iter()
+    .map(|r| r.subscription_token_hash)
+    .collect::<Vec<_>>();
+    assert_eq!(
+        remaining,
+        vec!["recently-expired", "recently-used", "valid"]
+    );
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn only_a_hash_of_the_token_is_stored(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    let link = confirmation_link_path(&outbox.emails()[0]);
+    let token = link
+        .strip_prefix("/subscriptions/confirm?subscription_token=")
+        .unwrap();
+
+    // The migration hashes the existing tokens the same way, with Postgres' own SHA-256.
+    let record = sqlx::query!(
+        r#"
+        SELECT
+            subscription_token_hash,
+            encode(sha256(convert_to($1, 'UTF8')), 'hex') as "expected_hash!"
+        FROM subscription_tokens
+        "#,
+        token,
+    )
+    .fetch_one(&db_pool)
+    .await?;
+    assert_ne!(record.subscription_token_hash, token);
+    assert_eq!(record.subscription_token_hash, record.expected_hash);
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn confirmation_results_are_shown_as_html_pages(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    let link = confirmation_link_path(&outbox.emails()[0]);
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    assert_eq!(
+        res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains("Your subscription is confirmed"));
+
+    let req = test::TestRequest::get()
+        .uri("/subscriptions/confirm?subscription_token=not-a-token")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains("This confirmation link is not valid."));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn used_confirmation_links_do_not_resubscribe_unsubscribed_addresses(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (outbox, email_sender) = test_outbox();
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let app = test::init_service(
+        App::new()
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(email_sender)
+            .app_data(web::Data::new(app_base_url)),
+    )
+    .await;
+
+    subscribe(&app).await;
+    let link = confirmation_link_path(&outbox.emails()[0]);
+    let req = test::TestRequest::get().uri(&link).to_request();
+    test::call_service(&app, req).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&db_pool)
+        .await?;
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains("This confirmation link has already been used."));
+
+    let record = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.status, "unsubscribed");
+
+    Ok(())
+}
@@ -0,0 +1,357 @@
+mod common;
+
+use actix_web::{
+    cookie::Key,
+    dev::Service,
+    http::{self, header::LOCATION},
+    test, web, App,
+};
+use fake::{faker::internet::en::SafeEmail, Fake, Faker};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{any, body_string_contains},
+    Mock, MockServer, ResponseTemplate,
+};
+use zero2prod::{
+    app_config, ApplicationBaseUrl, EmailSender, FlashMessagesFramework, LinkPurpose,
+    NewsletterTopics, SendGridClient, SubscriberEmail, SubscriberLinks,
+};
+
+fn subscriber_links() -> SubscriberLinks {
+    SubscriberLinks::new(
+        "http://127.0.0.1".to_string(),
+        "subscriber-links-test-secret",
+    )
+}
+
+async fn setup(
+    db_pool: &PgPool,
+) -> (
+    impl Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+    >,
+    MockServer,
+) {
+    let mock_server = MockServer::start().await;
+
+    let auth_token = Faker.fake();
+    let from = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+    let email_client: Arc<dyn EmailSender> =
+        Arc::new(SendGridClient::new(mock_server.uri(), auth_token, from));
+    let app_base_url = ApplicationBaseUrl("http://127.0.0.1".to_string());
+    let topics = NewsletterTopics(vec!["announcements".to_string(), "releases".to_string()]);
+
+    let app = test::init_service(
+        App::new()
+            .wrap(FlashMessagesFramework::new(Key::generate()))
+            .configure(app_config)
+            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::from(email_client))
+            .app_data(web::Data::new(app_base_url))
+            .app_data(web::Data::new(subscriber_links()))
+            .app_data(web::Data::new(topics)),
+    )
+    .await;
+
+    (app, mock_server)
+}
+
+async fn insert_confirmed_subscriber(db_pool: &PgPool, email: &str) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, 'le guin', now(), 'confirmed')
+        "#,
+        id,
+        email,
+    )
+    .execute(db_pool)
+    .await
+    .unwrap();
+    id
+}
+
+fn preferences_path(subscriber_id: Uuid) -> String {
+    subscriber_links()
+        .link(LinkPurpose::Preferences, subscriber_id)
+        .strip_prefix("http://127.0.0.1")
+        .unwrap()
+        .to_string()
+}
+
+#[sqlx::test]
+async fn the_preference_center_shows_the_current_preferences(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+
+    let req = test::TestRequest::get()
+        .uri(&preferences_path(subscriber_id))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let body = String::from_utf8(test::read_body(res).await.to_vec())?;
+    assert!(body.contains(r#"value="le guin""#));
+    assert!(body.contains(r#"value="ursula_le_guin@gmail.com""#));
+    assert!(body.contains(r#"value="announcements">"#));
+    assert!(body.contains(r#"value="immediate" selected"#));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn the_preference_center_rejects_tokens_for_other_purposes(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+    let token = subscriber_links().token(LinkPurpose::Unsubscribe, subscriber_id);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/subscriptions/preferences?token={token}"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/subscriptions/preferences?token={token}"))
+        .set_form([
+            ("name", "someone else"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("digest_frequency", "weekly"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn subscribers_can_change_their_name_topics_and_digest_frequency(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+    let path = preferences_path(subscriber_id);
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri(&path)
+        .set_form([
+            ("name", "Ursula K. Le Guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("topics", "announcements"),
+            ("topics", "releases"),
+            ("digest_frequency", "weekly"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(LOCATION).unwrap(), path.as_str());
+
+    let req = test::TestRequest::get()
+        .uri(&path)
+        .cookie(common::flash_cookie(&res).unwrap())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("Your preferences have been updated."));
+
+    let record = sqlx::query!("SELECT name, email, topics, digest_frequency FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.name, "Ursula K. Le Guin");
+    assert_eq!(record.email, "ursula_le_guin@gmail.com");
+    assert_eq!(record.topics, vec!["announcements", "releases"]);
+    assert_eq!(record.digest_frequency, "weekly");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn invalid_preferences_are_rejected(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, _) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+    let path = preferences_path(subscriber_id);
+
+    let test_cases = [
+        (
+            [
+                ("name", "<script>"),
+                ("email", "ursula_le_guin@gmail.com"),
+                ("topics", "releases"),
+                ("digest_frequency", "weekly"),
+            ],
+            "Invalid subscriber name: <script>",
+        ),
+        (
+            [
+                ("name", "le guin"),
+                ("email", "not-an-email"),
+                ("topics", "releases"),
+                ("digest_frequency", "weekly"),
+            ],
+            "Invalid email address: not-an-email",
+        ),
+        (
+            [
+                ("name", "le guin"),
+                ("email", "ursula_le_guin@gmail.com"),
+                ("topics", "gossip"),
+                ("digest_frequency", "weekly"),
+            ],
+            "Unknown topic: gossip",
+        ),
+        (
+            [
+                ("name", "le guin"),
+                ("email", "ursula_le_guin@gmail.com"),
+                ("topics", "releases"),
+                ("digest_frequency", "hourly"),
+            ],
+            "Invalid digest frequency: hourly",
+        ),
+    ];
+    for (form, message) in test_cases {
+        let req = test::TestRequest::post()
+            .uri(&path)
+            .set_form(form)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+
+        let req = test::TestRequest::get()
+            .uri(&path)
+            .cookie(common::flash_cookie(&res).unwrap())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body)?;
+        assert!(
+            body.contains(&htmlescape::encode_minimal(message)),
+            "The error for {message:?} was not shown."
+        );
+    }
+
+    let record = sqlx::query!("SELECT name, topics, digest_frequency FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.name, "le guin");
+    assert!(record.topics.is_empty());
+    assert_eq!(record.digest_frequency, "immediate");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn a_new_email_address_is_only_used_once_confirmed(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+
+    Mock::given(body_string_contains("le_guin@example.com"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri(&preferences_path(subscriber_id))
+        .set_form([
+            ("name", "le guin"),
+            ("email", "le_guin@example.com"),
+            ("digest_frequency", "immediate"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+    let record = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.email, "ursula_le_guin@gmail.com");
+
+    let email_request = &mock_server.received_requests().await.unwrap()[0];
+    let body = std::str::from_utf8(&email_request.body)?;
+    let confirmation_link = common::extract_links(body)[0].clone();
+    let link_uri = confirmation_link.strip_prefix("http://127.0.0.1").unwrap();
+    let req = test::TestRequest::get().uri(link_uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let record = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await?;
+    assert_eq!(record.email, "le_guin@example.com");
+    assert_eq!(record.status, "confirmed");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn the_email_address_of_another_subscriber_cannot_be_taken(
+    db_pool: PgPool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (app, mock_server) = setup(&db_pool).await;
+    let subscriber_id = insert_confirmed_subscriber(&db_pool, "ursula_le_guin@gmail.com").await;
+    insert_confirmed_subscriber(&db_pool, "le_guin@example.com").await;
+    let path = preferences_path(subscriber_id);
+
+    Mock::given(body_string_contains("le_guin@example.com"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let req = test::TestRequest::post()
+        .uri(&path)
+        .set_form([
+            ("name", "le guin"),
+            ("email", "le_guin@example.com"),
+            ("digest_frequency", "weekly"),
+        ])
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::SEE_OTHER);
+
+    // The response is the same as for an address nobody uses.
+    let req = test::TestRequest::get()
+        .uri(&path)
+        .cookie(common::flash_cookie(&res).unwrap())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("We have sent a confirmation link to le_guin@example.com"));
+    assert!(!body.contains("already subscribed"));
+
+    let email_request = &mock_server.received_requests().await.unwrap()[0];
+    let body = std::str::from_utf8(&email_request.body)?;
+    let confirmation_link = common::extract_links(body)[0].clone();
+    let link_uri = confirmation_link.strip_prefix("http://127.0.0.1").unwrap();
+    let req = test::TestRequest::get().uri(link_uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::CONFLICT);
+
+    let record = sqlx::query!(
+        "SELECT email FROM subscriptions WHERE id = $1",
+        subscriber_id
+    )
+    .fetch_one(&db_pool)
+    .await?;
+    assert_eq!(record.email, "ursula_le_guin@gmail.com");
+
+    Ok(())
+}